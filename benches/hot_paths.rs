@@ -0,0 +1,67 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+
+use rchip16::apu::Apu;
+use rchip16::capture::{HEIGHT, WIDTH};
+use rchip16::cpu::{Cpu, Instruction};
+use rchip16::gpu::Gpu;
+use rchip16::rom::RomBuilder;
+
+/// A loop of arithmetic, memory accesses and a jump
+fn loop_rom() -> Vec<u8> {
+    let program = [
+        Instruction::Addi { rx: 1, hhll: 1 },
+        Instruction::Muli { rx: 2, hhll: 3 },
+        Instruction::Stm { rx: 1, hhll: 0x1000 },
+        Instruction::Ldm { rx: 3, hhll: 0x1000 },
+        Instruction::Jmp { hhll: 0 },
+    ];
+    program.iter().flat_map(|instr| instr.encode().to_vec()).collect()
+}
+
+/// Executing instructions with and without the decode cache
+fn cpu_step(c: &mut Criterion) {
+    let mut group = c.benchmark_group("cpu_step");
+    for &(name, cache) in &[("decode_cache", true), ("no_decode_cache", false)] {
+        let mut cpu = Cpu::new(Gpu::new(), Apu::silent(1.0), &RomBuilder::new(loop_rom()).build());
+        cpu.set_decode_cache(cache);
+        group.bench_function(name, |b| b.iter(|| {
+            for _ in 0..1000 {
+                cpu.step();
+            }
+        }));
+    }
+    group.finish();
+}
+
+fn gpu_draw(c: &mut Criterion) {
+    let sprite: Vec<u8> = (0..8 * 16).map(|i| (i * 37 % 256) as u8).collect();
+    let mut gpu = Gpu::new();
+    gpu.set_sprite_size(8, 16);
+    c.bench_function("gpu_draw", |b| b.iter(|| {
+        // Sprites inside the screen, clipped at the edges and flipped
+        for (i, &(x, y)) in [(100, 100), (-8, 50), (310, 230), (160, -10)].iter().enumerate() {
+            gpu.set_hflip(i & 1 != 0);
+            gpu.set_vflip(i & 2 != 0);
+            black_box(gpu.draw(x, y, &sprite));
+        }
+    }));
+}
+
+fn gpu_render(c: &mut Criterion) {
+    let mut gpu = Gpu::new();
+    let mut buffer = vec![0; WIDTH * HEIGHT];
+    c.bench_function("gpu_render", |b| b.iter(|| gpu.render(&mut buffer)));
+}
+
+fn apu_samples(c: &mut Criterion) {
+    let mut apu = Apu::silent(1.0);
+    c.bench_function("apu_samples", |b| b.iter(|| {
+        // One frame of a triangle wave
+        apu.settings(0, 0, 15, 0, 15, 0);
+        apu.play(1000, 1000, true);
+        black_box(apu.samples(800))
+    }));
+}
+
+criterion_group!(benches, cpu_step, gpu_draw, gpu_render, apu_samples);
+criterion_main!(benches);
@@ -1,9 +1,8 @@
-use std::cmp::max;
-use std::sync::{Arc, Mutex};
+use std::cmp::{max, min};
+use std::f64::consts::PI;
+use std::sync::{Arc, Mutex, OnceLock};
 use std::thread;
 
-use cpal;
-
 use rand::{Rng, SeedableRng};
 use rand::rngs::SmallRng;
 use rand::distributions::Uniform;
@@ -12,15 +11,23 @@ static ATTACK: [u32; 16] = [2, 8, 16, 24, 38, 56, 68, 80, 100, 250, 500, 800, 10
 static DECAY: [u32; 16] = [6, 24, 48, 72, 114, 168, 204, 240, 300, 750, 1500, 2400, 3000, 9000, 15000, 24000];
 static RELEASE: [u32; 16] = [6, 24, 48, 72, 114, 168, 204, 240, 300, 750, 1500, 2400, 3000, 9000, 15000, 24000];
 
+/// The number of samples of a period in the wavetables of the band-limited waves
+const TABLE_SIZE: usize = 4096;
+
+/// The number of wavetables per wave, the last one has 512 harmonics which are still smooth at `TABLE_SIZE`
+const TABLE_LEVELS: usize = 10;
+
 pub struct Apu {
     max_volume: f32,
 
-    _handle: thread::JoinHandle<()>,
-    event_loop: Arc<cpal::EventLoop>,
-    stream_id: cpal::StreamId,
+    output: Option<Output>,
     gen: Arc<Mutex<Generator>>,
+    /// A copy of the generator that is advanced in emulated time instead of by the audio device
+    timeline: Generator,
 
     sample_rate: u32,
+    /// Flag to signal that the waves are band-limited instead of computed naively
+    band_limited: bool,
 
     wave: Wave,
     volume: f32,
@@ -30,14 +37,22 @@ pub struct Apu {
     release: usize,
 }
 
+/// The audio device the samples are played on
+struct Output {
+    _handle: thread::JoinHandle<()>,
+    event_loop: Arc<cpal::EventLoop>,
+    stream_id: cpal::StreamId,
+}
+
 impl Apu {
     /// Create a new audio processing
-    pub fn new(max_volume: f32) -> Apu {
-        let device = cpal::default_output_device().expect("Failed to get default output device");
-        let format = device.default_output_format().expect("Failed to get default output format");
+    pub fn new(max_volume: f32) -> Result<Apu, String> {
+        let device = cpal::default_output_device().ok_or("Failed to get default output device")?;
+        let format = device.default_output_format().map_err(|e| format!("Failed to get default output format: {}", e))?;
 
         let event_loop = Arc::new(cpal::EventLoop::new());
-        let stream_id = event_loop.build_output_stream(&device, &format).expect("Failed to build output stream");
+        let stream_id = event_loop.build_output_stream(&device, &format)
+            .map_err(|e| format!("Failed to build output stream: {}", e))?;
 
         let gen = Arc::new(Mutex::new(Generator::silence()));
 
@@ -47,7 +62,7 @@ impl Apu {
             let event_loop = event_loop.clone();
             let gen = gen.clone();
             thread::spawn(move || event_loop.run(|stream_id, data| {
-                let ref mut gen = *gen.lock().unwrap();
+                let gen = &mut *gen.lock().unwrap();
 
                 if gen.is_finished() {
                     event_loop.pause_stream(stream_id);
@@ -56,13 +71,13 @@ impl Apu {
                 match data {
                     cpal::StreamData::Output { buffer: cpal::UnknownTypeOutputBuffer::U16(mut buffer) } => {
                         for (sample, value) in buffer.chunks_mut(format.channels as usize).zip(gen) {
-                            let value = ((value * 0.5 + 0.5) * u16::max_value() as f32) as u16;
+                            let value = ((value * 0.5 + 0.5) * u16::MAX as f32) as u16;
                             for out in sample.iter_mut() { *out = value }
                         }
                     },
                     cpal::StreamData::Output { buffer: cpal::UnknownTypeOutputBuffer::I16(mut buffer) } => {
                         for (sample, value) in buffer.chunks_mut(format.channels as usize).zip(gen) {
-                            let value = (value * i16::max_value() as f32) as i16;
+                            let value = (value * i16::MAX as f32) as i16;
                             for out in sample.iter_mut() { *out = value }
                         }
                     },
@@ -76,15 +91,29 @@ impl Apu {
             }))
         };
 
+        let output = Output { _handle: handle, event_loop, stream_id };
+        Ok(Apu::with_output(max_volume, Some(output), gen, sample_rate))
+    }
+
+    /// Create a new audio processing that does not play any sound,
+    /// samples are still generated for `samples`
+    pub fn silent(max_volume: f32) -> Apu {
+        let gen = Arc::new(Mutex::new(Generator::silence()));
+        Apu::with_output(max_volume, None, gen, 48_000)
+    }
+
+    fn with_output(max_volume: f32, output: Option<Output>, gen: Arc<Mutex<Generator>>, sample_rate: u32) -> Apu {
+        // Compute the wavetables up front instead of in the audio thread when the first sound is played
+        wavetables();
         Apu {
             max_volume,
 
-            _handle: handle,
-            event_loop,
-            stream_id,
+            output,
             gen,
+            timeline: Generator::silence(),
 
             sample_rate,
+            band_limited: true,
 
             wave: Wave::Pulse,
             volume: 1.0,
@@ -104,6 +133,12 @@ impl Apu {
         self.wave = Wave::from_byte(wave).unwrap_or(Wave::Pulse);
     }
 
+    /// Enable or disable band-limited waves, which is enabled by default.
+    /// The raw waves are computed naively with a period of whole samples and alias at high frequencies
+    pub fn set_band_limited(&mut self, val: bool) {
+        self.band_limited = val;
+    }
+
     /// Play a sound with a frequency given in hz for a duration given in ms
     pub fn play(&mut self, frequency: u16, duration: u16, adsr: bool) {
         let volume = self.volume;
@@ -126,11 +161,10 @@ impl Apu {
             wave = Wave::Pulse;
         }
 
-        let mut gen = self.gen.lock().unwrap();
-        *gen = Generator {
+        let gen = Generator {
             volume: volume * self.max_volume,
             sustain: sustain * self.max_volume,
-            wave: wave,
+            wave,
 
             samples_attack: samples_attack as f32,
             samples_decay: samples_decay as f32,
@@ -138,16 +172,36 @@ impl Apu {
 
             samples_total: samples_total as f32,
             samples_period: (self.sample_rate / frequency as u32) as f32,
+            band_limited: self.band_limited,
+            phase_step: frequency as f32 / self.sample_rate as f32,
 
             samples_count: 0.0,
+            phase: 0.0,
         };
+        self.timeline = gen.clone();
+        *self.gen.lock().unwrap() = gen;
 
-        self.event_loop.play_stream(self.stream_id.clone());
+        if let Some(ref output) = self.output {
+            output.event_loop.play_stream(output.stream_id.clone());
+        }
     }
 
     /// Stop the currently playing sound
     pub fn stop(&mut self) {
-        self.event_loop.pause_stream(self.stream_id.clone());
+        self.timeline = Generator::silence();
+        if let Some(ref output) = self.output {
+            output.event_loop.pause_stream(output.stream_id.clone());
+        }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// Generate the next `count` samples of the current sound in emulated time,
+    /// independent of the audio device
+    pub fn samples(&mut self, count: usize) -> Vec<f32> {
+        self.timeline.by_ref().take(count).collect()
     }
 }
 
@@ -170,6 +224,7 @@ impl Wave {
         })
     }
 
+    /// The naive wave at sample `index` of a period given in samples
     fn sample(&mut self, index: f32, period: f32) -> f32 {
         match *self {
             Wave::Triangle => (4.0 / period) * ((index % period) - (period / 2.0)).abs() - 1.0,
@@ -178,8 +233,69 @@ impl Wave {
             Wave::Noise(ref mut rng) => rng.sample(Uniform::new_inclusive(-1.0, 1.0)),
         }
     }
+
+    /// The band-limited wave at `phase` (from 0 to 1) where `step` is the phase advanced per sample,
+    /// read from the wavetable with the most harmonics below the Nyquist frequency
+    fn band_limited(&mut self, phase: f32, step: f32) -> f32 {
+        let harmonics = (0.5 / step) as usize;
+        if harmonics == 0 {
+            return 0.0;
+        }
+        let level = min(harmonics.ilog2() as usize, TABLE_LEVELS - 1);
+        let table = match *self {
+            Wave::Triangle => &wavetables().triangle[level],
+            Wave::Sawtooth => &wavetables().sawtooth[level],
+            Wave::Pulse => &wavetables().pulse[level],
+            Wave::Noise(_) => return self.sample(phase, 1.0),
+        };
+        let pos = phase * TABLE_SIZE as f32;
+        let (i, frac) = (pos as usize % TABLE_SIZE, pos.fract());
+        table[i] + (table[(i + 1) % TABLE_SIZE] - table[i]) * frac
+    }
+}
+
+/// The periods of the band-limited waves, level `i` has the harmonics up to `2^i` of the Fourier series of the raw wave
+struct Wavetables {
+    triangle: Vec<Vec<f32>>,
+    sawtooth: Vec<Vec<f32>>,
+    pulse: Vec<Vec<f32>>,
+}
+
+impl Wavetables {
+    fn new() -> Wavetables {
+        let sin: Vec<f64> = (0..TABLE_SIZE).map(|i| (2.0 * PI * i as f64 / TABLE_SIZE as f64).sin()).collect();
+        // cos(x) = sin(x + pi / 2)
+        let cos = |i: usize| sin[(i + TABLE_SIZE / 4) % TABLE_SIZE];
+        let tables = |harmonic: &dyn Fn(usize, usize) -> f64| {
+            let mut wave = vec![0.0; TABLE_SIZE];
+            let mut levels = Vec::new();
+            for level in 0..TABLE_LEVELS {
+                // Add the harmonics between the ones of the previous level and `2^level`
+                for k in (1 << level >> 1) + 1..=1 << level {
+                    for (i, sample) in wave.iter_mut().enumerate() {
+                        *sample += harmonic(k, k * i % TABLE_SIZE);
+                    }
+                }
+                levels.push(wave.iter().map(|&s| s as f32).collect());
+            }
+            levels
+        };
+        let odd = |k: usize| (k & 1) as f64;
+        Wavetables {
+            triangle: tables(&|k, i| odd(k) * 8.0 / (PI * PI * (k * k) as f64) * cos(i)),
+            sawtooth: tables(&|k, i| -2.0 / (PI * k as f64) * sin[i]),
+            pulse: tables(&|k, i| odd(k) * 4.0 / (PI * k as f64) * sin[i]),
+        }
+    }
 }
 
+/// The wavetables, which are computed on first use
+fn wavetables() -> &'static Wavetables {
+    static WAVETABLES: OnceLock<Wavetables> = OnceLock::new();
+    WAVETABLES.get_or_init(Wavetables::new)
+}
+
+#[derive(Clone)]
 pub struct Generator {
     volume: f32,
     sustain: f32,
@@ -189,9 +305,15 @@ pub struct Generator {
     samples_decay: f32,
     samples_release: f32,
     samples_total: f32,
+    /// The period of the raw waves in whole samples
     samples_period: f32,
+    band_limited: bool,
+    /// The frequency of the band-limited waves in periods per sample
+    phase_step: f32,
 
     samples_count: f32,
+    /// The position within the period of the band-limited waves from 0 to 1
+    phase: f32,
 }
 
 impl Generator {
@@ -205,7 +327,10 @@ impl Generator {
             samples_release: 0.0,
             samples_total: 0.0,
             samples_period: 0.0,
+            band_limited: false,
+            phase_step: 0.0,
             samples_count: 0.0,
+            phase: 0.0,
         }
     }
 
@@ -222,7 +347,13 @@ impl Iterator for Generator {
             return Some(0.0);
         }
 
-        let mut sample = self.wave.sample(self.samples_count, self.samples_period);
+        let mut sample = if self.band_limited {
+            let sample = self.wave.band_limited(self.phase, self.phase_step);
+            self.phase = (self.phase + self.phase_step) % 1.0;
+            sample
+        } else {
+            self.wave.sample(self.samples_count, self.samples_period)
+        };
 
         if self.samples_count < self.samples_attack { // Attack
             sample *= self.volume * (self.samples_count / self.samples_attack);
@@ -238,3 +369,100 @@ impl Iterator for Generator {
         Some(sample)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::apu::*;
+
+    /// The number of samples analysed by the spectral tests, a power of 2
+    const SPECTRUM_SIZE: usize = 4096;
+
+    /// Generate the samples of a wave at full volume
+    fn generate(wave: Wave, band_limited: bool, frequency: f32, sample_rate: f32) -> Vec<f32> {
+        let gen = Generator {
+            volume: 1.0,
+            sustain: 1.0,
+            wave,
+            samples_total: SPECTRUM_SIZE as f32,
+            samples_period: sample_rate / frequency,
+            band_limited,
+            phase_step: frequency / sample_rate,
+            ..Generator::silence()
+        };
+        gen.take(SPECTRUM_SIZE).collect()
+    }
+
+    /// The share of the energy that lies between the harmonics of `frequency` in dB,
+    /// which is where the aliases of the harmonics above the Nyquist frequency fold back to
+    fn aliasing(samples: &[f32], frequency: f32, sample_rate: f32) -> f64 {
+        let n = samples.len();
+        // A 4-term Blackman-Harris window, its leakage is far below the measured aliasing
+        let window = |i: usize| {
+            let x = 2.0 * PI * i as f64 / n as f64;
+            0.35875 - 0.48829 * x.cos() + 0.14128 * (2.0 * x).cos() - 0.01168 * (3.0 * x).cos()
+        };
+        let windowed: Vec<f64> = samples.iter().enumerate().map(|(i, &s)| s as f64 * window(i)).collect();
+        let (cos, sin): (Vec<f64>, Vec<f64>) = (0..n).map(|i| {
+            let x = 2.0 * PI * i as f64 / n as f64;
+            (x.cos(), x.sin())
+        }).unzip();
+        let twiddle = |k: usize, i: usize| (k * i) & (n - 1);
+
+        let bin_width = sample_rate as f64 / n as f64;
+        let harmonics = frequency as f64 / bin_width;
+        let (mut total, mut aliased) = (0.0, 0.0);
+        for k in 0..n / 2 {
+            let (mut re, mut im) = (0.0, 0.0);
+            for (i, &s) in windowed.iter().enumerate() {
+                re += s * cos[twiddle(k, i)];
+                im -= s * sin[twiddle(k, i)];
+            }
+            let energy = re * re + im * im;
+            total += energy;
+            let nearest = (k as f64 / harmonics).round() * harmonics;
+            if (k as f64 - nearest).abs() > 5.0 {
+                aliased += energy;
+            }
+        }
+        10.0 * (aliased / total).log10()
+    }
+
+    #[test]
+    fn band_limited_waves_do_not_alias() {
+        // At 44.1 kHz the periods are no whole number of samples, so the aliases fall between the harmonics
+        let rate = 44_100.0;
+        for &frequency in &[440.0, 1500.0, 4000.0] {
+            for wave in &[Wave::Triangle, Wave::Sawtooth, Wave::Pulse] {
+                let raw = generate(wave.clone(), false, frequency, rate);
+                let band_limited = generate(wave.clone(), true, frequency, rate);
+                let (raw_aliasing, aliasing) = (aliasing(&raw, frequency, rate), aliasing(&band_limited, frequency, rate));
+                assert!(aliasing < -80.0 && aliasing < raw_aliasing - 20.0,
+                        "{} Hz: {:.1} dB aliasing, raw {:.1} dB", frequency, aliasing, raw_aliasing);
+
+                // The harmonics below the Nyquist frequency carry nearly all of the energy
+                let energy = |samples: &[f32]| samples.iter().map(|&s| (s * s) as f64).sum::<f64>();
+                assert!((10.0 * (energy(&band_limited) / energy(&raw)).log10()).abs() < 1.0);
+            }
+        }
+        assert!(generate(Wave::Sawtooth, true, 30_000.0, rate).iter().all(|&s| s == 0.0));
+    }
+
+    #[test]
+    fn samples_follow_emulated_time() {
+        let mut apu = Apu::silent(1.0);
+        apu.set_band_limited(false);
+        assert!(apu.samples(10).iter().all(|&s| s == 0.0));
+
+        // 10 ms at 48 kHz are 480 samples with a period of 96 samples
+        apu.play(500, 10, false);
+        let samples = apu.samples(500);
+        assert_eq!(samples.len(), 500);
+        assert_eq!(&samples[..2], &[1.0, 1.0]);
+        assert_eq!(samples[48], -1.0);
+        assert!(samples[480..].iter().all(|&s| s == 0.0));
+
+        apu.play(500, 10, false);
+        apu.stop();
+        assert!(apu.samples(10).iter().all(|&s| s == 0.0));
+    }
+}
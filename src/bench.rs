@@ -0,0 +1,98 @@
+use std::time::{Duration, Instant};
+
+use clap::ArgMatches;
+use serde::Serialize;
+
+use crate::apu::Apu;
+use crate::capture::{HEIGHT, WIDTH};
+use crate::cli;
+use crate::cpu::Cpu;
+use crate::gpu::Gpu;
+use crate::rom::Rom;
+
+/// The number of instructions per frame at 1 MHz
+const FRAME_INSTRUCTIONS: u64 = 1_000_000 / 60;
+
+/// The number of frames that are run if not given on the command line, 10 seconds of emulated time
+const DEFAULT_FRAMES: u64 = 600;
+
+/// The result of a benchmark, as printed by the `bench` subcommand
+#[derive(Serialize)]
+struct Report<'a> {
+    file: &'a str,
+    frames: u64,
+    instructions: u64,
+    seconds: f64,
+    frames_per_second: f64,
+    instructions_per_second: f64,
+    /// The clock rate that is emulated, the original runs at 1 MHz
+    mhz: f64,
+    cpu_seconds: f64,
+    draw_seconds: f64,
+    render_seconds: f64,
+}
+
+/// Run a rom headless and as fast as possible for a number of frames and report the performance
+pub fn bench(matches: &ArgMatches) -> Result<(), String> {
+    let file = matches.value_of("ROM").unwrap();
+    let rom = Rom::load(file).map_err(|e| format!("Error loading rom file {}: {}", file, e))?;
+    let frames = cli::value(matches, "frames").unwrap_or(DEFAULT_FRAMES);
+
+    let mut cpu = Cpu::new(Gpu::new(), Apu::silent(1.0), &rom);
+    cpu.set_seed(0);
+    cpu.set_decode_cache(!matches.is_present("no-decode-cache"));
+    cpu.gpu_mut().set_profile(true);
+    let mut buffer = vec![0; WIDTH * HEIGHT];
+
+    let (mut instructions, mut step_time, mut render_time) = (0, Duration::ZERO, Duration::ZERO);
+    let start = Instant::now();
+    for _ in 0..frames {
+        let frame_start = Instant::now();
+        for _ in 0..FRAME_INSTRUCTIONS {
+            cpu.step();
+            instructions += 1;
+            if let Some(trap) = cpu.take_trap() {
+                return Err(trap.to_string());
+            }
+            if cpu.wait_vblank() {
+                break;
+            }
+        }
+        let render_start = Instant::now();
+        step_time += render_start - frame_start;
+        cpu.render(&mut buffer);
+        render_time += render_start.elapsed();
+    }
+    let seconds = start.elapsed().as_secs_f64();
+
+    // Sprites are drawn while the instructions are executed, so their time is part of the steps
+    let draw_time = cpu.gpu().draw_time();
+    let per_second = instructions as f64 / seconds;
+    let report = Report {
+        file,
+        frames,
+        instructions,
+        seconds,
+        frames_per_second: frames as f64 / seconds,
+        instructions_per_second: per_second,
+        mhz: per_second / 1e6,
+        cpu_seconds: step_time.saturating_sub(draw_time).as_secs_f64(),
+        draw_seconds: draw_time.as_secs_f64(),
+        render_seconds: render_time.as_secs_f64(),
+    };
+
+    if matches.is_present("json") {
+        println!("{}", serde_json::to_string_pretty(&report).map_err(|e| e.to_string())?);
+    } else {
+        let share = |time: f64| format!("{:.3} s ({:.1}%)", time, time / seconds * 100.0);
+        println!("      frames: {}", report.frames);
+        println!("instructions: {}", report.instructions);
+        println!("        time: {:.3} s", report.seconds);
+        println!("       speed: {:.0} instructions/s, {:.2} MHz emulated", per_second, report.mhz);
+        println!("    frames/s: {:.0} ({:.1}x real time)", report.frames_per_second, report.frames_per_second / 60.0);
+        println!("         cpu: {}", share(report.cpu_seconds));
+        println!("        draw: {}", share(report.draw_seconds));
+        println!("      render: {}", share(report.render_seconds));
+    }
+    Ok(())
+}
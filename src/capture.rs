@@ -0,0 +1,71 @@
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use png::{BitDepth, ColorType, Encoder};
+
+/// The width of a frame in pixels
+pub const WIDTH: usize = 320;
+/// The height of a frame in pixels
+pub const HEIGHT: usize = 240;
+
+/// Write a frame of palette indices as PNG,
+/// either as true color image or as indexed image with the palette embedded
+pub fn write_png<W: Write>(w: W, frame: &[u8], palette: &[u32; 16], indexed: bool) -> io::Result<()> {
+    let mut encoder = Encoder::new(w, WIDTH as u32, HEIGHT as u32);
+
+    let data = if indexed {
+        encoder.set_color(ColorType::Indexed);
+        encoder.set_depth(BitDepth::Four);
+        encoder.set_palette(palette.iter().flat_map(|&c| rgb(c).to_vec()).collect());
+        frame.chunks(2).map(|p| p[0] << 4 | p[1]).collect::<Vec<_>>()
+    } else {
+        encoder.set_color(ColorType::RGB);
+        encoder.set_depth(BitDepth::Eight);
+        frame.iter().flat_map(|&p| rgb(palette[p as usize]).to_vec()).collect()
+    };
+
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&data)?;
+    Ok(())
+}
+
+/// Split a color of the form `0xRRGGBB` into its components
+pub fn rgb(color: u32) -> [u8; 3] {
+    [(color >> 16) as u8, (color >> 8) as u8, color as u8]
+}
+
+/// Find the first file of the form `STEM-N.EXT` that does not exist yet
+pub fn next_path<P: AsRef<Path>>(stem: P, ext: &str) -> PathBuf {
+    let stem = stem.as_ref().to_string_lossy();
+    (1..)
+        .map(|n| PathBuf::from(format!("{}-{}.{}", stem, n, ext)))
+        .find(|path| !path.exists())
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::capture::*;
+
+    #[test]
+    fn rgb_works() {
+        assert_eq!(rgb(0xBF3932), [0xBF, 0x39, 0x32]);
+    }
+
+    #[test]
+    fn write_png_works() {
+        let mut frame = vec![0; WIDTH * HEIGHT];
+        frame[1] = 15;
+        let palette = [0xFFFFFF; 16];
+
+        let mut rgb = Vec::new();
+        write_png(&mut rgb, &frame, &palette, false).unwrap();
+        let mut indexed = Vec::new();
+        write_png(&mut indexed, &frame, &palette, true).unwrap();
+
+        assert_eq!(&rgb[1..4], b"PNG");
+        assert_eq!(&indexed[1..4], b"PNG");
+        assert!(indexed.windows(4).any(|w| w == b"PLTE"));
+        assert!(!rgb.windows(4).any(|w| w == b"PLTE"));
+    }
+}
@@ -0,0 +1,219 @@
+use std::str::FromStr;
+
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+
+use crate::config::{PaletteSetting, Profile};
+use crate::cpu::{DIV_ZERO_POLICIES, STRICT_MODES};
+use crate::gpu::PALETTES;
+use crate::rom::Version;
+use crate::scale::FILTERS;
+
+/// Build the command line interface
+pub fn app<'a, 'b>() -> App<'a, 'b> {
+    App::new("rchip16")
+        .version(env!("CARGO_PKG_VERSION"))
+        .about("A chip16 emulator")
+        .setting(AppSettings::ColoredHelp)
+        .setting(AppSettings::SubcommandsNegateReqs)
+        .setting(AppSettings::ArgsNegateSubcommands)
+        .arg(Arg::with_name("ROM")
+            .help("The rom file to run")
+            .required(true)
+            .index(1))
+        .arg(Arg::with_name("config")
+            .long("config")
+            .value_name("FILE")
+            .help("Read the settings from FILE instead of rchip16.toml"))
+        .arg(Arg::with_name("break")
+            .long("break")
+            .help("Enter the debugger before the first instruction"))
+        .arg(Arg::with_name("unlimited")
+            .long("unlimited")
+            .help("Run as fast as possible instead of 1 MHz"))
+        .arg(Arg::with_name("div-zero")
+            .long("div-zero")
+            .value_name("POLICY")
+            .possible_values(&DIV_ZERO_POLICIES)
+            .help("What happens when the rom divides by 0 (default: trap)"))
+        .arg(Arg::with_name("stack-guard")
+            .long("stack-guard")
+            .help("Enter the debugger on stack overflows, underflows and misaligned stack pointers"))
+        .arg(Arg::with_name("strict")
+            .long("strict")
+            .value_name("MODE")
+            .possible_values(&STRICT_MODES)
+            .help("Warn about or trap on behaviour that is not defined by the specification"))
+        .arg(Arg::with_name("scale")
+            .long("scale")
+            .value_name("N")
+            .possible_values(&["1", "2", "3", "4", "5", "6", "7", "8"])
+            .help("The scale of the window"))
+        .arg(Arg::with_name("window")
+            .long("window")
+            .value_name("WxH")
+            .validator(|v| parse_size(&v).map(|_| ()))
+            .help("The size of the window, the frame is scaled by the largest integer factor that fits"))
+        .arg(Arg::with_name("filter")
+            .long("filter")
+            .value_name("FILTER")
+            .possible_values(&FILTERS)
+            .help("The filter applied before scaling"))
+        .arg(Arg::with_name("palette")
+            .long("palette")
+            .value_name("PALETTE")
+            .possible_values(&PALETTES)
+            .help("Force a palette that overrides the ones loaded by the rom"))
+        .arg(Arg::with_name("volume")
+            .long("volume")
+            .value_name("V")
+            .validator(validate::<f32>)
+            .help("The audio volume between 0 and 1"))
+        .arg(Arg::with_name("raw-waves")
+            .long("raw-waves")
+            .help("Play naive waves instead of band-limited ones, which alias at high frequencies"))
+        .arg(Arg::with_name("speed")
+            .long("speed")
+            .value_name("S")
+            .validator(validate::<f32>)
+            .help("A multiplier of the frame rate"))
+        .arg(Arg::with_name("seed")
+            .long("seed")
+            .value_name("SEED")
+            .validator(validate::<u64>)
+            .help("Seed the random number generator used by RND"))
+        .arg(Arg::with_name("headless")
+            .long("headless")
+            .help("Run without a window and without audio"))
+        .arg(Arg::with_name("frames")
+            .long("frames")
+            .value_name("N")
+            .validator(validate::<u64>)
+            .help("Quit after N frames have been rendered"))
+        .arg(Arg::with_name("record")
+            .long("record")
+            .value_name("FILE")
+            .help("Record the video as Y4M to FILE and the audio as WAV next to it"))
+        .arg(Arg::with_name("gif")
+            .long("gif")
+            .value_name("FILE")
+            .help("Record an animated GIF to FILE"))
+        .arg(no_decode_cache())
+        .arg(Arg::with_name("trace")
+            .long("trace")
+            .help("Print every instruction before it is executed"))
+        .subcommand(SubCommand::with_name("info")
+            .about("Print and validate the header of a rom, exits with 1 if it is not valid")
+            .arg(Arg::with_name("ROM")
+                .help("The rom file to check")
+                .required(true)
+                .index(1))
+            .arg(Arg::with_name("json")
+                .long("json")
+                .help("Print the report as JSON")))
+        .subcommand(SubCommand::with_name("pack")
+            .about("Wrap a raw binary into a rom with a CH16 header or repair the header of a rom")
+            .arg(Arg::with_name("INPUT")
+                .help("The raw binary or rom")
+                .required(true)
+                .index(1))
+            .arg(Arg::with_name("OUTPUT")
+                .help("The rom file to write")
+                .required(true)
+                .index(2))
+            .arg(Arg::with_name("spec")
+                .long("spec")
+                .value_name("VERSION")
+                .validator(validate::<Version>)
+                .help("The specification version (default: 1.3 or the one of INPUT)"))
+            .arg(Arg::with_name("start")
+                .long("start")
+                .value_name("ADDR")
+                .validator(|v| parse_address(&v).map(|_| ()))
+                .help("The start address (default: 0 or the one of INPUT)")))
+        .subcommand(SubCommand::with_name("unpack")
+            .about("Strip the CH16 header from a rom")
+            .arg(Arg::with_name("INPUT")
+                .help("The rom file")
+                .required(true)
+                .index(1))
+            .arg(Arg::with_name("OUTPUT")
+                .help("The raw binary to write")
+                .required(true)
+                .index(2)))
+        .subcommand(SubCommand::with_name("disasm")
+            .about("Print the instructions of a rom")
+            .arg(Arg::with_name("ROM")
+                .help("The rom file to disassemble")
+                .required(true)
+                .index(1)))
+        .subcommand(SubCommand::with_name("bench")
+            .about("Run a rom headless without frame limit and report the emulation speed")
+            .arg(Arg::with_name("ROM")
+                .help("The rom file to run")
+                .required(true)
+                .index(1))
+            .arg(Arg::with_name("frames")
+                .long("frames")
+                .value_name("N")
+                .validator(validate::<u64>)
+                .help("The number of frames to run (default: 600)"))
+            .arg(no_decode_cache())
+            .arg(Arg::with_name("json")
+                .long("json")
+                .help("Print the report as JSON")))
+}
+
+/// The flag to disable the decode cache, shared by running and benchmarking a rom
+fn no_decode_cache<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("no-decode-cache")
+        .long("no-decode-cache")
+        .help("Decode every instruction when it is executed instead of caching it, which is slower")
+}
+
+/// Collect the settings that override the configuration file
+pub fn overrides(matches: &ArgMatches) -> Profile {
+    let flag = |name| if matches.is_present(name) { Some(true) } else { None };
+    Profile {
+        scale: value(matches, "scale"),
+        window: matches.value_of("window").and_then(|v| parse_size(v).ok()),
+        filter: value(matches, "filter"),
+        force_palette: matches.value_of("palette").map(|name| PaletteSetting::Name(name.into())),
+        volume: value(matches, "volume"),
+        raw_waves: flag("raw-waves"),
+        speed: value(matches, "speed"),
+        unlimited: flag("unlimited"),
+        div_zero: value(matches, "div-zero"),
+        stack_guard: flag("stack-guard"),
+        strict: value(matches, "strict"),
+        break_start: flag("break"),
+        ..Profile::default()
+    }
+}
+
+/// Get the parsed value of an option, the value has already been validated
+pub fn value<T: FromStr>(matches: &ArgMatches, name: &str) -> Option<T> {
+    matches.value_of(name).and_then(|v| v.parse().ok())
+}
+
+/// Parse a size given as `WIDTHxHEIGHT`
+pub fn parse_size(v: &str) -> Result<[usize; 2], String> {
+    let mut parts = v.splitn(2, 'x').map(|p| p.parse().ok());
+    match (parts.next().flatten(), parts.next().flatten()) {
+        (Some(w), Some(h)) => Ok([w, h]),
+        _ => Err(format!("invalid size '{}'", v)),
+    }
+}
+
+/// Parse an address given either in decimal or in hex with a `0x` prefix
+pub fn parse_address(v: &str) -> Result<u16, String> {
+    let res = if v.starts_with("0x") || v.starts_with("0X") {
+        u16::from_str_radix(&v[2..], 16)
+    } else {
+        v.parse()
+    };
+    res.map_err(|_| format!("invalid address '{}'", v))
+}
+
+fn validate<T: FromStr>(v: String) -> Result<(), String> {
+    v.parse::<T>().map(|_| ()).map_err(|_| format!("invalid value '{}'", v))
+}
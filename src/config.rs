@@ -0,0 +1,372 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use minifb::Key;
+use serde::Deserialize;
+
+use crate::capture::{HEIGHT, WIDTH};
+use crate::cpu::{DivZero, Strict};
+use crate::gpu::{named_palette, PALETTES};
+use crate::scale::Filter;
+
+/// The configuration file that is used if no other file has been specified
+pub const DEFAULT_CONFIG: &str = "rchip16.toml";
+
+/// Keys are set in the order Up, Down, Left, Right, Select, Start, A, B
+static DEFAULT_CONTROLLERS: [[Key; 8]; 2] = [
+    [Key::Up, Key::Down, Key::Left, Key::Right, Key::RightShift, Key::Enter, Key::N, Key::M],
+    [Key::W, Key::S, Key::A, Key::D, Key::LeftShift, Key::Tab, Key::X, Key::C],
+];
+
+/// The contents of a configuration file
+#[derive(Deserialize, Default)]
+pub struct Config {
+    /// Settings used for every rom
+    #[serde(flatten)]
+    global: Profile,
+    /// Settings for a single rom, keyed by its CRC32 checksum in hex
+    #[serde(default)]
+    roms: HashMap<String, Profile>,
+}
+
+/// A set of settings where every value is optional
+#[derive(Deserialize, Default, Clone)]
+pub struct Profile {
+    pub scale: Option<u8>,
+    pub window: Option<[usize; 2]>,
+    pub filter: Option<String>,
+    pub volume: Option<f32>,
+    pub raw_waves: Option<bool>,
+    pub speed: Option<f32>,
+    pub palette: Option<[u32; 16]>,
+    pub force_palette: Option<PaletteSetting>,
+    pub unlimited: Option<bool>,
+    pub div_zero: Option<String>,
+    pub stack_guard: Option<bool>,
+    pub strict: Option<String>,
+    #[serde(rename = "break")]
+    pub break_start: Option<bool>,
+    pub controller1: Option<Bindings>,
+    pub controller2: Option<Bindings>,
+}
+
+/// A palette given either by name (e.g. `"high-contrast"`) or as 16 colors
+#[derive(Deserialize, Clone)]
+#[serde(untagged)]
+pub enum PaletteSetting {
+    Name(String),
+    Colors([u32; 16]),
+}
+
+/// The key bindings of a controller given as key names (e.g. `"Up"`, `"LeftShift"`, `"N"`)
+#[derive(Deserialize, Default, Clone)]
+pub struct Bindings {
+    pub up: Option<String>,
+    pub down: Option<String>,
+    pub left: Option<String>,
+    pub right: Option<String>,
+    pub select: Option<String>,
+    pub start: Option<String>,
+    pub a: Option<String>,
+    pub b: Option<String>,
+}
+
+/// The final settings after all profiles have been applied
+pub struct Settings {
+    pub scale: u8,
+    /// The size of the window, the frame is scaled by the largest integer factor that fits
+    pub window: Option<(usize, usize)>,
+    pub filter: Filter,
+    pub volume: f32,
+    /// Play naive waves instead of band-limited ones, which alias at high frequencies
+    pub raw_waves: bool,
+    pub speed: f32,
+    pub palette: Option<[u32; 16]>,
+    /// A palette that overrides the default palette and the ones loaded by the rom
+    pub force_palette: Option<[u32; 16]>,
+    pub unlimited: bool,
+    /// What happens when the rom divides by 0
+    pub div_zero: DivZero,
+    /// Enter the debugger on stack overflows, underflows and misaligned stack pointers
+    pub stack_guard: bool,
+    /// How behaviour that is not defined by the specification is reported
+    pub strict: Strict,
+    pub break_start: bool,
+    /// Keys of controller 1 & 2 in the order Up, Down, Left, Right, Select, Start, A, B
+    pub controllers: [[Key; 8]; 2],
+}
+
+impl Config {
+    /// Load a configuration file
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Config, String> {
+        let contents = fs::read_to_string(path).map_err(|e| e.to_string())?;
+        Config::parse(&contents)
+    }
+
+    /// Parse the contents of a configuration file
+    pub fn parse(contents: &str) -> Result<Config, String> {
+        let config: Config = toml::from_str(contents).map_err(|e| e.to_string())?;
+        for crc in config.roms.keys() {
+            parse_crc(crc)?;
+        }
+        Ok(config)
+    }
+
+    /// Resolve the settings for the rom with the given checksum,
+    /// values are taken from the defaults, the global profile, the rom profile and `overrides` in that order
+    pub fn settings(&self, crc: u32, overrides: &Profile) -> Result<Settings, String> {
+        let mut profile = self.global.clone();
+        for (key, rom) in &self.roms {
+            if parse_crc(key)? == crc {
+                profile.merge(rom);
+            }
+        }
+        profile.merge(overrides);
+        profile.resolve()
+    }
+}
+
+impl Profile {
+    /// Override all values with the ones that are set in `other`
+    pub fn merge(&mut self, other: &Profile) {
+        merge(&mut self.scale, &other.scale);
+        merge(&mut self.window, &other.window);
+        merge(&mut self.filter, &other.filter);
+        merge(&mut self.volume, &other.volume);
+        merge(&mut self.raw_waves, &other.raw_waves);
+        merge(&mut self.speed, &other.speed);
+        merge(&mut self.palette, &other.palette);
+        merge(&mut self.force_palette, &other.force_palette);
+        merge(&mut self.unlimited, &other.unlimited);
+        merge(&mut self.div_zero, &other.div_zero);
+        merge(&mut self.stack_guard, &other.stack_guard);
+        merge(&mut self.strict, &other.strict);
+        merge(&mut self.break_start, &other.break_start);
+        for (this, other) in [(&mut self.controller1, &other.controller1), (&mut self.controller2, &other.controller2)] {
+            if let Some(other) = other {
+                this.get_or_insert_with(Bindings::default).merge(other);
+            }
+        }
+    }
+
+    /// Turn the profile into settings, using the defaults for missing values
+    fn resolve(self) -> Result<Settings, String> {
+        let scale = self.scale.unwrap_or(2);
+        if !(1..=8).contains(&scale) {
+            return Err(format!("Unsupported scale {} (expected 1 to 8)", scale));
+        }
+
+        let window = self.window.map(|[w, h]| (w, h));
+        if let Some((w, h)) = window {
+            if w < WIDTH || h < HEIGHT {
+                return Err(format!("Window size {}x{} is smaller than {}x{}", w, h, WIDTH, HEIGHT));
+            }
+        }
+
+        let filter = self.filter.as_ref().map_or(Ok(Filter::None), |f| f.parse())?;
+
+        let volume = self.volume.unwrap_or(0.1);
+        if !(0.0..=1.0).contains(&volume) {
+            return Err(format!("Volume {} is not between 0 and 1", volume));
+        }
+
+        let speed = self.speed.unwrap_or(1.0);
+        if !(0.01..=100.0).contains(&speed) {
+            return Err(format!("Speed {} is not between 0.01 and 100", speed));
+        }
+
+        let force_palette = match self.force_palette {
+            Some(PaletteSetting::Name(name)) => Some(named_palette(&name).ok_or_else(|| {
+                format!("Unknown palette {} (expected one of {})", name, PALETTES.join(", "))
+            })?),
+            Some(PaletteSetting::Colors(colors)) => Some(colors),
+            None => None,
+        };
+
+        let div_zero = self.div_zero.as_ref().map_or(Ok(DivZero::Trap), |p| p.parse())?;
+        let strict = self.strict.as_ref().map_or(Ok(Strict::Off), |m| m.parse())?;
+
+        let mut controllers = DEFAULT_CONTROLLERS;
+        for (keys, bindings) in controllers.iter_mut().zip([self.controller1, self.controller2]) {
+            if let Some(bindings) = bindings {
+                bindings.apply(keys)?;
+            }
+        }
+
+        Ok(Settings {
+            scale,
+            window,
+            filter,
+            volume,
+            raw_waves: self.raw_waves.unwrap_or(false),
+            speed,
+            palette: self.palette,
+            force_palette,
+            unlimited: self.unlimited.unwrap_or(false),
+            div_zero,
+            stack_guard: self.stack_guard.unwrap_or(false),
+            strict,
+            break_start: self.break_start.unwrap_or(false),
+            controllers,
+        })
+    }
+}
+
+impl Settings {
+    /// The size of the window, which is either given or the scaled size of a frame
+    pub fn window_size(&self) -> (usize, usize) {
+        self.window.unwrap_or((WIDTH * self.scale as usize, HEIGHT * self.scale as usize))
+    }
+}
+
+impl Bindings {
+    fn merge(&mut self, other: &Bindings) {
+        for (this, other) in self.buttons_mut().iter_mut().zip(other.buttons().iter()) {
+            merge(this, other);
+        }
+    }
+
+    /// Overwrite the keys of a controller with the bound ones
+    fn apply(&self, keys: &mut [Key; 8]) -> Result<(), String> {
+        for (key, name) in keys.iter_mut().zip(self.buttons()) {
+            if let Some(name) = name {
+                *key = parse_key(name).ok_or_else(|| format!("Unknown key {}", name))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn buttons(&self) -> [&Option<String>; 8] {
+        [&self.up, &self.down, &self.left, &self.right, &self.select, &self.start, &self.a, &self.b]
+    }
+
+    fn buttons_mut(&mut self) -> [&mut Option<String>; 8] {
+        [&mut self.up, &mut self.down, &mut self.left, &mut self.right,
+         &mut self.select, &mut self.start, &mut self.a, &mut self.b]
+    }
+}
+
+fn merge<T: Clone>(this: &mut Option<T>, other: &Option<T>) {
+    if other.is_some() {
+        this.clone_from(other);
+    }
+}
+
+/// Parse a CRC32 checksum given in hex with an optional `0x` prefix
+fn parse_crc(s: &str) -> Result<u32, String> {
+    let hex = s.trim_start_matches("0x").trim_start_matches("0X");
+    u32::from_str_radix(hex, 16).map_err(|_| format!("Invalid rom checksum {}", s))
+}
+
+/// Find a key by its name, ignoring case
+pub fn parse_key(name: &str) -> Option<Key> {
+    use Key::*;
+
+    static KEYS: [Key; 106] = [
+        Key0, Key1, Key2, Key3, Key4, Key5, Key6, Key7, Key8, Key9,
+        A, B, C, D, E, F, G, H, I, J, K, L, M, N, O, P, Q, R, S, T, U, V, W, X, Y, Z,
+        F1, F2, F3, F4, F5, F6, F7, F8, F9, F10, F11, F12, F13, F14, F15,
+        Down, Left, Right, Up, Apostrophe, Backquote, Backslash, Comma, Equal, LeftBracket,
+        Minus, Period, RightBracket, Semicolon, Slash, Backspace, Delete, End, Enter, Escape,
+        Home, Insert, Menu, PageDown, PageUp, Pause, Space, Tab, NumLock, CapsLock, ScrollLock,
+        LeftShift, RightShift, LeftCtrl, RightCtrl,
+        NumPad0, NumPad1, NumPad2, NumPad3, NumPad4, NumPad5, NumPad6, NumPad7, NumPad8, NumPad9,
+        NumPadDot, NumPadSlash, NumPadAsterisk, NumPadMinus, NumPadPlus, NumPadEnter,
+        LeftAlt, RightAlt, LeftSuper, RightSuper,
+    ];
+
+    KEYS.iter().cloned().find(|key| format!("{:?}", key).eq_ignore_ascii_case(name))
+}
+
+#[cfg(test)]
+mod tests {
+    use minifb::Key;
+
+    use crate::config::*;
+
+    #[test]
+    fn parse_key_works() {
+        assert_eq!(parse_key("LeftShift"), Some(Key::LeftShift));
+        assert_eq!(parse_key("numpad5"), Some(Key::NumPad5));
+        assert_eq!(parse_key("Key7"), Some(Key::Key7));
+        assert_eq!(parse_key("Unknown"), None);
+    }
+
+    #[test]
+    fn defaults_are_used() {
+        let settings = Config::default().settings(0, &Profile::default()).unwrap();
+        assert_eq!(settings.volume, 0.1);
+        assert_eq!(settings.window_size(), (640, 480));
+        assert_eq!(settings.filter, Filter::None);
+        assert_eq!(settings.controllers, DEFAULT_CONTROLLERS);
+        assert!(!settings.unlimited);
+        assert!(!settings.raw_waves);
+        assert_eq!(settings.div_zero, DivZero::Trap);
+        assert!(!settings.stack_guard);
+        assert_eq!(settings.strict, Strict::Off);
+    }
+
+    #[test]
+    fn rom_profile_overrides_global() {
+        let config = Config::parse(r#"
+            volume = 0.5
+            speed = 2.0
+
+            [controller1]
+            a = "Z"
+
+            [roms.414FA339]
+            volume = 0.8
+            break = true
+            div_zero = "carry"
+
+            [roms.414FA339.controller1]
+            b = "X"
+        "#).unwrap();
+
+        let settings = config.settings(0x414FA339, &Profile::default()).unwrap();
+        assert_eq!(settings.volume, 0.8);
+        assert_eq!(settings.speed, 2.0);
+        assert!(settings.break_start);
+        assert_eq!(settings.div_zero, DivZero::Carry);
+        assert_eq!(settings.controllers[0][6], Key::Z);
+        assert_eq!(settings.controllers[0][7], Key::X);
+
+        let settings = config.settings(0x12345678, &Profile::default()).unwrap();
+        assert_eq!(settings.volume, 0.5);
+        assert!(!settings.break_start);
+        assert_eq!(settings.controllers[0][7], Key::M);
+
+        let overrides = Profile { volume: Some(0.0), ..Profile::default() };
+        let settings = config.settings(0x414FA339, &overrides).unwrap();
+        assert_eq!(settings.volume, 0.0);
+    }
+
+    #[test]
+    fn invalid_values_are_rejected() {
+        assert!(Config::parse("[roms.nothex]").is_err());
+        assert!(Config::parse("[controller2]\nup = \"Nope\"").unwrap().settings(0, &Profile::default()).is_err());
+        assert!(Config::parse("scale = 9").unwrap().settings(0, &Profile::default()).is_err());
+        assert!(Config::parse("window = [300, 200]").unwrap().settings(0, &Profile::default()).is_err());
+        assert!(Config::parse("filter = \"blur\"").unwrap().settings(0, &Profile::default()).is_err());
+        assert!(Config::parse("force_palette = \"sepia\"").unwrap().settings(0, &Profile::default()).is_err());
+        assert!(Config::parse("div_zero = \"ignore\"").unwrap().settings(0, &Profile::default()).is_err());
+        assert!(Config::parse("strict = \"panic\"").unwrap().settings(0, &Profile::default()).is_err());
+        assert!(Config::parse("speed = 0.0").unwrap().settings(0, &Profile::default()).is_err());
+        assert!(Config::parse("speed = 1e-30").unwrap().settings(0, &Profile::default()).is_err());
+        assert!(Config::parse("speed = nan").unwrap().settings(0, &Profile::default()).is_err());
+        assert!(Config::parse("speed = inf").unwrap().settings(0, &Profile::default()).is_err());
+    }
+
+    #[test]
+    fn force_palette_accepts_names_and_colors() {
+        let config = Config::parse("force_palette = \"colorblind\"").unwrap();
+        let settings = config.settings(0, &Profile::default()).unwrap();
+        assert_eq!(settings.force_palette, named_palette("colorblind"));
+
+        let config = Config::parse("force_palette = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16]").unwrap();
+        let settings = config.settings(0, &Profile::default()).unwrap();
+        assert_eq!(settings.force_palette.unwrap()[15], 16);
+    }
+}
@@ -1,4 +1,12 @@
 mod instructions;
+#[cfg(test)]
+mod reference;
+
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::fmt;
+use std::mem;
+use std::str::FromStr;
 
 use rand::SeedableRng;
 use rand::rngs::SmallRng;
@@ -9,6 +17,89 @@ use crate::rom::Rom;
 use crate::util::*;
 
 use instructions::*;
+pub use instructions::Instruction;
+
+/// The start of the stack
+const STACK_START: u16 = 0xFDF0;
+/// The end of the stack (exclusive), where the I/O ports start
+const STACK_END: u16 = 0xFFF0;
+
+/// `len` bytes of the memory starting at `addr`, wrapping around at the end of the memory
+fn wrapping_slice(memory: &[u8], addr: u16, len: usize) -> Cow<'_, [u8]> {
+    let addr = addr as usize;
+    if addr + len <= memory.len() {
+        Cow::Borrowed(&memory[addr..addr + len])
+    } else {
+        Cow::Owned((0..len).map(|i| memory[(addr + i) % memory.len()]).collect())
+    }
+}
+
+/// What happens when `DIV`, `MOD` or `REM` divide by 0
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum DivZero {
+    /// The instruction is skipped and a trap is raised, which enters the debugger
+    Trap,
+    /// The result is 0 and the zero flag is set
+    Zero,
+    /// The result is 0, the zero flag is set and the carry flag is set to signal the error
+    Carry,
+}
+
+pub static DIV_ZERO_POLICIES: [&str; 3] = ["trap", "zero", "carry"];
+
+impl FromStr for DivZero {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<DivZero, String> {
+        Ok(match s.to_lowercase().as_str() {
+            "trap" => DivZero::Trap,
+            "zero" => DivZero::Zero,
+            "carry" => DivZero::Carry,
+            _ => return Err(format!("Unknown division by zero policy {} (expected one of {})",
+                                    s, DIV_ZERO_POLICIES.join(", "))),
+        })
+    }
+}
+
+/// How behaviour that is not defined by the specification is reported
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum Strict {
+    /// Nothing is reported
+    Off,
+    /// A warning is reported once per instruction address
+    Warn,
+    /// A trap is raised, which enters the debugger
+    Trap,
+}
+
+pub static STRICT_MODES: [&str; 3] = ["off", "warn", "trap"];
+
+impl FromStr for Strict {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Strict, String> {
+        Ok(match s.to_lowercase().as_str() {
+            "off" => Strict::Off,
+            "warn" => Strict::Warn,
+            "trap" => Strict::Trap,
+            _ => return Err(format!("Unknown strict mode {} (expected one of {})", s, STRICT_MODES.join(", "))),
+        })
+    }
+}
+
+/// An error of the running rom that should be looked at in the debugger
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct Trap {
+    /// The address of the instruction that raised the trap
+    pub pc: u16,
+    pub reason: String,
+}
+
+impl fmt::Display for Trap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at PC 0x{:04X}", self.reason, self.pc)
+    }
+}
 
 pub struct Cpu {
     /// CPU registers
@@ -23,6 +114,23 @@ pub struct Cpu {
     rng: SmallRng,
     /// Flag to signal that the cpu is waiting for `VBLNK`
     wait_vblank: bool,
+    /// The address of the instruction that is executed
+    instr_pc: u16,
+    /// The trap raised by the last instruction
+    trap: Option<Trap>,
+    div_zero: DivZero,
+    /// Raise traps for stack overflows, underflows and misaligned stack pointers
+    stack_guard: bool,
+    strict: Strict,
+    /// The warnings about undefined behaviour that have not been taken yet
+    warnings: Vec<Trap>,
+    /// The addresses of the instructions that have been warned about
+    warned: HashSet<u16>,
+    /// The size of the rom, which is not meant to be written to
+    rom_size: usize,
+    /// The decoded instruction at each address, entries are cleared when their memory is written
+    cache: Vec<Option<Instruction>>,
+    decode_cache: bool,
 }
 
 struct Registers {
@@ -40,21 +148,31 @@ impl Cpu {
     pub fn new(gpu: Gpu, apu: Apu, rom: &Rom) -> Cpu {
         let regs = Registers {
             pc: rom.start(),
-            sp: 0xFDF0,
+            sp: STACK_START,
             r: [0; 16],
             flags: 0,
         };
 
         let mut memory = vec![0; 2usize.pow(16)];
-        memory[..rom.size() as usize].copy_from_slice(rom.rom());
+        memory[..rom.rom().len()].copy_from_slice(rom.rom());
 
         Cpu {
-            regs: regs,
-            memory: memory,
-            gpu: gpu,
-            apu: apu,
+            regs,
+            memory,
+            gpu,
+            apu,
             rng: SmallRng::from_entropy(),
             wait_vblank: false,
+            instr_pc: 0,
+            trap: None,
+            div_zero: DivZero::Trap,
+            stack_guard: false,
+            strict: Strict::Off,
+            warnings: Vec::new(),
+            warned: HashSet::new(),
+            rom_size: rom.rom().len(),
+            cache: vec![None; 0x10000],
+            decode_cache: true,
         }
     }
 
@@ -64,34 +182,147 @@ impl Cpu {
 
         // Fetch `pc`, increase `pc` and run instruction at `pc`
         let pc = self.regs.pc;
-        self.regs.pc += 4;
-        if let Err(e) = run_instruction(self, pc as usize) {
-            panic!("Invalid instruction at 0x{:02X} ({})", pc, e);
+        self.instr_pc = pc;
+        self.regs.pc = pc.wrapping_add(4);
+        let result = if self.decode_cache {
+            let decoded = match self.cache[pc as usize] {
+                Some(instr) => Ok(instr),
+                None => Instruction::decode(&self.fetch(pc)).inspect(|&instr| self.cache[pc as usize] = Some(instr)),
+            };
+            decoded.map(|instr| execute(self, instr))
+        } else {
+            run_instruction(self, pc)
+        };
+        // Invalid instructions are skipped
+        if let Err(e) = result {
+            self.trap(format!("invalid instruction ({})", e));
         }
 
         self.gpu.set_vblank(false);
     }
 
+    /// Seed the random number generator used by `RND`
+    pub fn set_seed(&mut self, seed: u64) {
+        self.rng = SmallRng::seed_from_u64(seed);
+    }
+
+    /// Enable or disable the cache of decoded instructions, which is enabled by default
+    pub fn set_decode_cache(&mut self, val: bool) {
+        self.decode_cache = val;
+        self.cache.iter_mut().for_each(|instr| *instr = None);
+    }
+
+    /// Clear the decoded instructions that include the byte at `addr`
+    fn invalidate(&mut self, addr: u16) {
+        for i in 0..4 {
+            self.cache[addr.wrapping_sub(i) as usize] = None;
+        }
+    }
+
+    /// Set what happens when dividing by 0
+    pub fn set_div_zero(&mut self, policy: DivZero) {
+        self.div_zero = policy;
+    }
+
+    /// Enable traps for stack overflows into the I/O ports, underflows and misaligned stack pointers
+    pub fn set_stack_guard(&mut self, val: bool) {
+        self.stack_guard = val;
+    }
+
+    /// Raise a trap for the instruction that is executed, only the first trap of an instruction is kept
+    fn trap(&mut self, reason: String) {
+        if self.trap.is_none() {
+            self.trap = Some(Trap { pc: self.instr_pc, reason });
+        }
+    }
+
+    /// Set how behaviour that is not defined by the specification is reported
+    pub fn set_strict(&mut self, mode: Strict) {
+        self.strict = mode;
+    }
+
+    /// Report behaviour of the executed instruction that is not defined by the specification,
+    /// `reason` is only formatted when the behaviour is reported
+    fn undefined<F: FnOnce() -> String>(&mut self, reason: F) {
+        match self.strict {
+            Strict::Off => {},
+            Strict::Warn => if self.warned.insert(self.instr_pc) {
+                self.warnings.push(Trap { pc: self.instr_pc, reason: reason() });
+            },
+            Strict::Trap => self.trap(reason()),
+        }
+    }
+
+    /// Take the warnings about undefined behaviour reported since the last call
+    pub fn take_warnings(&mut self) -> Vec<Trap> {
+        mem::take(&mut self.warnings)
+    }
+
+    /// Take the trap raised by the last instruction if any
+    pub fn take_trap(&mut self) -> Option<Trap> {
+        self.trap.take()
+    }
+
+    /// Decode the instruction at the specified address
+    pub fn instruction(&self, addr: u16) -> Result<Instruction, String> {
+        Instruction::decode(&self.fetch(addr))
+    }
+
     pub fn format_instruction(&self, addr: u16) -> Result<String, String> {
-        format_instruction(self, addr as usize)
+        self.instruction(addr).map(|instr| instr.to_string())
+    }
+
+    /// The 4 bytes of the instruction at the specified address, wrapping around at the end of the memory
+    fn fetch(&self, addr: u16) -> [u8; 4] {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(&wrapping_slice(&self.memory, addr, 4));
+        bytes
     }
 
-    /// Read one value from the memory at the specified address
+    /// Read one value from the memory at the specified address, wrapping around at the end of the memory
     pub fn read<T: Copy>(&self, addr: u16) -> T {
-        *deserialize(&self.memory[addr as usize..])
+        *deserialize(&wrapping_slice(&self.memory, addr, mem::size_of::<T>()))
     }
 
-    /// Write one value to the memory at the specified address
+    /// Write one value to the memory at the specified address, wrapping around at the end of the memory
     pub fn write<T>(&mut self, addr: u16, val: T) {
-        let addr = addr as usize;
+        let len = self.memory.len();
         let buf = serialize(&val);
-        self.memory[addr..addr + buf.len()].copy_from_slice(buf);
+        let in_rom = |i| (addr as usize + i) % len < self.rom_size;
+        if self.strict != Strict::Off && (0..buf.len()).any(in_rom) {
+            self.undefined(|| format!("write to the rom at 0x{:04X}", addr));
+        }
+        for (i, &byte) in buf.iter().enumerate() {
+            self.memory[(addr as usize + i) % len] = byte;
+            self.invalidate(addr.wrapping_add(i as u16));
+        }
     }
 
     pub fn render(&mut self, buffer: &mut [u32]) {
         self.gpu.render(buffer);
     }
 
+    pub fn gpu(&self) -> &Gpu {
+        &self.gpu
+    }
+
+    pub fn gpu_mut(&mut self) -> &mut Gpu {
+        &mut self.gpu
+    }
+
+    /// The whole 64 KB of memory
+    pub fn memory(&self) -> &[u8] {
+        &self.memory
+    }
+
+    pub fn apu(&self) -> &Apu {
+        &self.apu
+    }
+
+    pub fn apu_mut(&mut self) -> &mut Apu {
+        &mut self.apu
+    }
+
     /// Get the carry flag
     pub fn carry(&self) -> bool {
         bitflag(self.regs.flags, 1)
@@ -151,6 +382,8 @@ impl Cpu {
     pub fn set_input(&mut self, (one, two): (u8, u8)) {
         self.memory[0xFFF0] = one;
         self.memory[0xFFF2] = two;
+        self.invalidate(0xFFF0);
+        self.invalidate(0xFFF2);
     }
 
     pub fn wait_vblank(&self) -> bool {
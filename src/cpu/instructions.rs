@@ -1,48 +1,115 @@
-use std::fmt::Write;
+use std::fmt;
 
 use rand::Rng;
 
-use super::Cpu;
+use super::{wrapping_slice, Cpu, DivZero, STACK_END, STACK_START};
 use crate::util::*;
 
 macro_rules! instructions {
-    ($( $byte:pat => ($mnemonic:expr, [$( $parm:ident ),*], $action:expr) ),*) => {
-        pub fn run_instruction(cpu: &mut Cpu, addr: usize) -> Result<(), String> {
-            Ok(match cpu.memory[addr] {
-                $( $byte => run_instruction!($action, [$($parm),*], cpu, cpu.memory[addr..]), )*
-                _ => return Err(format!("Unknown Opcode 0x{:02X}", cpu.memory[addr]))
-            })
+    ($( $byte:literal => $name:ident($mnemonic:expr, [$( $parm:ident ),*], $action:expr) ),*) => {
+        /// A fully decoded instruction with its operands
+        #[derive(PartialEq, Eq, Clone, Copy, Debug)]
+        pub enum Instruction {
+            $( $name { $( $parm: parm_type!($parm) ),* }, )*
         }
 
-        pub fn format_instruction(cpu: &Cpu, addr: usize) -> Result<String, String> {
-            #[allow(unused_mut, unused_must_use)]
-            Ok(match cpu.memory[addr] {
-                $( $byte => format_instruction!($mnemonic, [$($parm),*], cpu.memory[addr..]), )*
-                _ => return Err(format!("Unknown Opcode 0x{:02X}", cpu.memory[addr]))
-            })
+        impl Instruction {
+            /// Decode the first 4 bytes of a slice as an instruction
+            pub fn decode(bytes: &[u8]) -> Result<Instruction, String> {
+                if bytes.len() < 4 {
+                    return Err(format!("Truncated instruction of {} bytes", bytes.len()));
+                }
+                Ok(match bytes[0] {
+                    $( $byte => Instruction::$name { $( $parm: parm!($parm, bytes) ),* }, )*
+                    _ => return Err(format!("Unknown Opcode 0x{:02X}", bytes[0]))
+                })
+            }
+
+            /// Encode the instruction into its 4 bytes, the unused bits are 0
+            pub fn encode(&self) -> [u8; 4] {
+                let mut bytes = [self.opcode(), 0, 0, 0];
+                match *self {
+                    $( Instruction::$name { $( $parm ),* } => { $( encode_parm!($parm, $parm, bytes); )* }, )*
+                }
+                bytes
+            }
+
+            pub fn opcode(&self) -> u8 {
+                match self {
+                    $( Instruction::$name { .. } => $byte, )*
+                }
+            }
+
+            /// The operands of the instruction by kind
+            fn operands(&self) -> Operands {
+                match *self {
+                    $( Instruction::$name { $( $parm ),* } => Operands { $( $parm: Some($parm), )* ..Operands::default() }, )*
+                }
+            }
+        }
+
+        impl fmt::Display for Instruction {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                match *self {
+                    $( Instruction::$name { $( $parm ),* } => format_instruction!(f, $mnemonic, [$( $parm ),*]), )*
+                }
+            }
+        }
+
+        /// Execute a decoded instruction
+        pub fn execute(cpu: &mut Cpu, instr: Instruction) {
+            match instr {
+                $( Instruction::$name { $( $parm ),* } => $action(cpu, $( $parm ),* ), )*
+            }
         }
     };
 }
 
-macro_rules! run_instruction {
-    ($action:expr, [$( $parm:ident ),*], $cpu:expr, $bytes:expr) => {{
-        $( let $parm = parm!($parm, $bytes); )*
-        $action($cpu, $( $parm ),* )
-    }};
+/// Decode and execute the instruction at `addr`
+pub fn run_instruction(cpu: &mut Cpu, addr: u16) -> Result<(), String> {
+    let instr = Instruction::decode(&cpu.fetch(addr))?;
+    execute(cpu, instr);
+    Ok(())
+}
+
+macro_rules! parm_type {
+    (hhll) => { u16 };
+    ($parm:ident) => { u8 };
+}
+
+macro_rules! operands {
+    ($( $parm:ident ),*) => {
+        /// The operands of an instruction, `None` for the kinds it does not have
+        #[derive(Default)]
+        struct Operands {
+            $( $parm: Option<parm_type!($parm)>, )*
+        }
+
+        impl Instruction {
+            $(
+                #[doc = concat!("The `", stringify!($parm), "` operand, `None` if the instruction does not have it")]
+                pub fn $parm(&self) -> Option<parm_type!($parm)> {
+                    self.operands().$parm
+                }
+            )*
+        }
+    };
 }
 
 macro_rules! format_instruction {
-    ($mnemonic:expr, [cond, $( $parm:ident ),*], $bytes:expr) => {{
-        let mut ret = format!("{}", $mnemonic);
-        format_parm!(cond, $bytes, &mut ret);
-        $( ret.push(' '); format_parm!($parm, $bytes, &mut ret); )*
-        ret
-    }};
-    ($mnemonic:expr, [$( $parm:ident ),*], $bytes:expr) => {{
-        let mut ret = format!("{}", $mnemonic);
-        $( ret.push(' '); format_parm!($parm, $bytes, &mut ret); )*
-        ret
+    ($f:expr, $mnemonic:expr, [$first:ident $(, $parm:ident )*]) => {{
+        write!($f, "{}{}", $mnemonic, separator!($first))?;
+        format_parm!($first, $first, $f)?;
+        $( write!($f, " ")?; format_parm!($parm, $parm, $f)?; )*
+        Ok(())
     }};
+    ($f:expr, $mnemonic:expr, []) => { write!($f, "{}", $mnemonic) };
+}
+
+/// The condition is appended to the mnemonic (e.g. `JNZ`), all other operands are separated by a space
+macro_rules! separator {
+    (cond) => { "" };
+    ($parm:ident) => { " " };
 }
 
 macro_rules! parm {
@@ -56,12 +123,23 @@ macro_rules! parm {
     (hh, $bytes:expr) => { $bytes[3] };
 }
 
+macro_rules! encode_parm {
+    (rx, $val:expr, $bytes:expr) => { $bytes[1] |= $val & 0x0F };
+    (ry, $val:expr, $bytes:expr) => { $bytes[1] |= ($val & 0x0F) << 4 };
+    (rz, $val:expr, $bytes:expr) => { $bytes[2] |= $val & 0x0F };
+    (hhll, $val:expr, $bytes:expr) => { $bytes[2..].copy_from_slice(serialize(&$val)) };
+    (cond, $val:expr, $bytes:expr) => { $bytes[1] = $val };
+    (ad, $val:expr, $bytes:expr) => { $bytes[1] = $val };
+    (ll, $val:expr, $bytes:expr) => { $bytes[2] = $val };
+    (hh, $val:expr, $bytes:expr) => { $bytes[3] = $val };
+}
+
 macro_rules! format_parm {
-    (rx, $bytes:expr, $writer:expr) => { write!($writer, "R{:X}", parm!(rx, $bytes)) };
-    (ry, $bytes:expr, $writer:expr) => { write!($writer, "R{:X}", parm!(ry, $bytes)) };
-    (rz, $bytes:expr, $writer:expr) => { write!($writer, "R{:X}", parm!(rz, $bytes)) };
-    (cond, $bytes:expr, $writer:expr) => {
-        let cond = match parm!(cond, $bytes) {
+    (rx, $val:expr, $f:expr) => { write!($f, "R{:X}", $val) };
+    (ry, $val:expr, $f:expr) => { write!($f, "R{:X}", $val) };
+    (rz, $val:expr, $f:expr) => { write!($f, "R{:X}", $val) };
+    (cond, $val:expr, $f:expr) => {{
+        let cond = match $val {
             0x0 => "Z",
             0x1 => "NZ",
             0x2 => "N",
@@ -79,98 +157,161 @@ macro_rules! format_parm {
             0xE => "LE",
             _ => "UNKNOWN",
         };
-        write!($writer, "{}", cond)
-    };
-    ($parm:ident, $bytes:expr, $writer:expr) => { write!($writer, "{}", parm!($parm, $bytes)) };
+        write!($f, "{}", cond)
+    }};
+    ($parm:ident, $val:expr, $f:expr) => { write!($f, "{}", $val) };
 }
 
 instructions! {
-    0x00 => ("NOP", [], |_| {}),
-    0x01 => ("CLS", [], cls),
-    0x02 => ("VBLNK", [], vblnk),
-    0x03 => ("BGC", [ll], bgc),
-    0x04 => ("SPR", [ll, hh], spr),
-    0x05 => ("DRW", [rx, ry, hhll], drw),
-    0x06 => ("DRW", [rx, ry, rz], drw_r),
-    0x07 => ("RND", [rx, hhll], rnd),
-    0x08 => ("FLIP", [hh], flip),
-    0x09 => ("SND0", [], snd0),
-    0x0A => ("SND1", [hhll], snd1),
-    0x0B => ("SND2", [hhll], snd2),
-    0x0C => ("SND3", [hhll], snd3),
-    0x0D => ("SNP", [rx, hhll], snp),
-    0x0E => ("SNG", [ad, ll, hh], sng),
-    0x10 => ("JMP", [hhll], jmp),
-    0x11 => ("JMC", [hhll], |cpu, hhll| jx(cpu, 0x9, hhll)),
-    0x12 => ("J", [cond, hhll], jx),
-    0x13 => ("JME", [rx, ry, hhll], jme),
-    0x14 => ("CALL", [hhll], call),
-    0x15 => ("RET", [], ret),
-    0x16 => ("JMP_R", [rx], jmp_r),
-    0x17 => ("C", [cond, hhll], cx),
-    0x18 => ("CALL", [rx], call_r),
-    0x20 => ("LDI", [rx, hhll], ldi_r),
-    0x21 => ("LDI", [hhll], ldi_sp),
-    0x22 => ("LDM", [rx, hhll], ldm),
-    0x23 => ("LDM", [rx, ry], ldm_r),
-    0x24 => ("MOV", [rx, ry], mov),
-    0x30 => ("STM", [rx, hhll], stm),
-    0x31 => ("STM", [rx, ry], stm_r),
-    0x40 => ("ADDI", [rx, hhll], |cpu, rx, hhll| math(cpu, rx, hhll, add)),
-    0x41 => ("ADD", [rx, ry], |cpu, rx, ry| math_r(cpu, rx, ry, rx, add)),
-    0x42 => ("ADD", [rx, ry, rz], |cpu, rx, ry, rz| math_r(cpu, rx, ry, rz, add)),
-    0x50 => ("SUBI", [rx, hhll], |cpu, rx, hhll| math(cpu, rx, hhll, sub)),
-    0x51 => ("SUB", [rx, ry], |cpu, rx, ry| math_r(cpu, rx, ry, rx, sub)),
-    0x52 => ("SUB", [rx, ry, rz], |cpu, rx, ry, rz| math_r(cpu, rx, ry, rz, sub)),
-    0x53 => ("CMPI", [rx, hhll], |cpu, rx, hhll| check(cpu, rx, hhll, sub)),
-    0x54 => ("CMP", [rx, ry], |cpu, rx, ry| check_r(cpu, rx, ry, sub)),
-    0x60 => ("ANDI", [rx, hhll], |cpu, rx, hhll| math(cpu, rx, hhll, |_, a, b| a & b)),
-    0x61 => ("AND", [rx, ry], |cpu, rx, ry| math_r(cpu, rx, ry, rx, |_, a, b| a & b)),
-    0x62 => ("AND", [rx, ry, rz], |cpu, rx, ry, rz| math_r(cpu, rx, ry, rz, |_, a, b| a & b)),
-    0x63 => ("TSTI", [rx, hhll], |cpu, rx, hhll| check(cpu, rx, hhll, |_, a, b| a & b)),
-    0x64 => ("TST", [rx, ry], |cpu, rx, ry| check_r(cpu, rx, ry, |_, a, b| a & b)),
-    0x70 => ("ORI", [rx, hhll], |cpu, rx, hhll| math(cpu, rx, hhll, |_, a, b| a | b)),
-    0x71 => ("OR", [rx, ry], |cpu, rx, ry| math_r(cpu, rx, ry, rx, |_, a, b| a | b)),
-    0x72 => ("OR", [rx, ry, rz], |cpu, rx, ry, rz| math_r(cpu, rx, ry, rz, |_, a, b| a | b)),
-    0x80 => ("XORI", [rx, hhll], |cpu, rx, hhll| math(cpu, rx, hhll, |_, a, b| a ^ b)),
-    0x81 => ("XOR", [rx, ry], |cpu, rx, ry| math_r(cpu, rx, ry, rx, |_, a, b| a ^ b)),
-    0x82 => ("XOR", [rx, ry, rz], |cpu, rx, ry, rz| math_r(cpu, rx, ry, rz, |_, a, b| a ^ b)),
-    0x90 => ("MULI", [rx, hhll], |cpu, rx, hhll| math(cpu, rx, hhll, mul)),
-    0x91 => ("MUL", [rx, ry], |cpu, rx, ry| math_r(cpu, rx, ry, rx, mul)),
-    0x92 => ("MUL", [rx, ry, rz], |cpu, rx, ry, rz| math_r(cpu, rx, ry, rz, mul)),
-    0xA0 => ("DIVI", [rx, hhll], |cpu, rx, hhll| math(cpu, rx, hhll, div)),
-    0xA1 => ("DIV", [rx, ry], |cpu, rx, ry| math_r(cpu, rx, ry, rx, div)),
-    0xA2 => ("DIV", [rx, ry, rz], |cpu, rx, ry, rz| math_r(cpu, rx, ry, rz, div)),
-    0xA3 => ("MODI", [rx, hhll], |cpu, rx, hhll| math(cpu, rx, hhll, |_, a, b| (a % b + b) % b)),
-    0xA4 => ("MOD", [rx, ry], |cpu, rx, ry| math_r(cpu, rx, ry, rx, |_, a, b| (a % b + b) % b)),
-    0xA5 => ("MOD", [rx, ry, rz], |cpu, rx, ry, rz| math_r(cpu, rx, ry, rz, |_, a, b| (a % b + b) % b)),
-    0xA6 => ("REMI", [rx, hhll], |cpu, rx, hhll| math(cpu, rx, hhll, |_, a, b| a % b)),
-    0xA7 => ("REM", [rx, ry], |cpu, rx, ry| math_r(cpu, rx, ry, rx, |_, a, b| a % b)),
-    0xA8 => ("REM", [rx, ry, rz], |cpu, rx, ry, rz| math_r(cpu, rx, ry, rz, |_, a, b| a % b)),
-    0xB0 => ("SHL", [rx, hhll], |cpu, rx, hhll| math(cpu, rx, hhll, |_, a, b| a << b)),
-    0xB1 => ("SHR", [rx, hhll], |cpu, rx, hhll| math(cpu, rx, hhll, |_, a, b| (a as u16 >> b) as i16)),
-    0xB2 => ("SAR", [rx, hhll], |cpu, rx, hhll| math(cpu, rx, hhll, |_, a, b| a >> b)),
-    0xB3 => ("SHL", [rx, ry], |cpu, rx, ry| math_r(cpu, rx, ry, rx, |_, a, b| a << b)),
-    0xB4 => ("SHR", [rx, ry], |cpu, rx, ry| math_r(cpu, rx, ry, rx, |_, a, b| (a as u16 >> b) as i16)),
-    0xB5 => ("SAR", [rx, ry], |cpu, rx, ry| math_r(cpu, rx, ry, rx, |_, a, b| a >> b)),
-    0xC0 => ("PUSH", [rx], push),
-    0xC1 => ("POP", [rx], pop),
-    0xC2 => ("PUSHALL", [], pushall),
-    0xC3 => ("POPALL", [], popall),
-    0xC4 => ("PUSHF", [], pushf),
-    0xC5 => ("POPF", [], popf),
-    0xD0 => ("PAL", [hhll], pal),
-    0xD1 => ("PAL", [rx], pal_r),
-    0xE0 => ("NOTI", [rx, hhll], |cpu, rx, hhll| math(cpu, rx, hhll, |_, _, b| !b)),
-    0xE1 => ("NOT", [rx], |cpu, rx| math_r(cpu, rx, 0, rx, |_, a, _| !a)),
-    0xE2 => ("NOT", [rx, ry], |cpu, rx, ry| math_r(cpu, 0, ry, rx, |_, _, b| !b)),
-    0xE3 => ("NEGI", [rx, hhll], |cpu, rx, hhll| math(cpu, rx, hhll, |_, _, b| -b)),
-    0xE4 => ("NEG", [rx], |cpu, rx| math_r(cpu, rx, 0, rx, |_, a, _| -a)),
-    0xE5 => ("NEG", [rx, ry], |cpu, rx, ry| math_r(cpu, 0, ry, rx, |_, _, b| -b))
-}
-
-/// Test a given condition and return the result
-fn condition(cpu: &Cpu, cond: u8) -> bool {
+    0x00 => Nop("NOP", [], |_| {}),
+    0x01 => Cls("CLS", [], cls),
+    0x02 => Vblnk("VBLNK", [], vblnk),
+    0x03 => Bgc("BGC", [ll], bgc),
+    0x04 => Spr("SPR", [ll, hh], spr),
+    0x05 => Drw("DRW", [rx, ry, hhll], drw),
+    0x06 => DrwR("DRW", [rx, ry, rz], drw_r),
+    0x07 => Rnd("RND", [rx, hhll], rnd),
+    0x08 => Flip("FLIP", [hh], flip),
+    0x09 => Snd0("SND0", [], snd0),
+    0x0A => Snd1("SND1", [hhll], snd1),
+    0x0B => Snd2("SND2", [hhll], snd2),
+    0x0C => Snd3("SND3", [hhll], snd3),
+    0x0D => Snp("SNP", [rx, hhll], snp),
+    0x0E => Sng("SNG", [ad, ll, hh], sng),
+    0x10 => Jmp("JMP", [hhll], jmp),
+    0x11 => Jmc("JMC", [hhll], |cpu, hhll| jx(cpu, 0x9, hhll)),
+    0x12 => Jx("J", [cond, hhll], jx),
+    0x13 => Jme("JME", [rx, ry, hhll], jme),
+    0x14 => Call("CALL", [hhll], call),
+    0x15 => Ret("RET", [], ret),
+    0x16 => JmpR("JMP_R", [rx], jmp_r),
+    0x17 => Cx("C", [cond, hhll], cx),
+    0x18 => CallR("CALL", [rx], call_r),
+    0x20 => Ldi("LDI", [rx, hhll], ldi_r),
+    0x21 => LdiSp("LDI", [hhll], ldi_sp),
+    0x22 => Ldm("LDM", [rx, hhll], ldm),
+    0x23 => LdmR("LDM", [rx, ry], ldm_r),
+    0x24 => Mov("MOV", [rx, ry], mov),
+    0x30 => Stm("STM", [rx, hhll], stm),
+    0x31 => StmR("STM", [rx, ry], stm_r),
+    0x40 => Addi("ADDI", [rx, hhll], |cpu, rx, hhll| math(cpu, rx, hhll, add)),
+    0x41 => Add("ADD", [rx, ry], |cpu, rx, ry| math_r(cpu, rx, ry, rx, add)),
+    0x42 => Add3("ADD", [rx, ry, rz], |cpu, rx, ry, rz| math_r(cpu, rx, ry, rz, add)),
+    0x50 => Subi("SUBI", [rx, hhll], |cpu, rx, hhll| math(cpu, rx, hhll, sub)),
+    0x51 => Sub("SUB", [rx, ry], |cpu, rx, ry| math_r(cpu, rx, ry, rx, sub)),
+    0x52 => Sub3("SUB", [rx, ry, rz], |cpu, rx, ry, rz| math_r(cpu, rx, ry, rz, sub)),
+    0x53 => Cmpi("CMPI", [rx, hhll], |cpu, rx, hhll| check(cpu, rx, hhll, sub)),
+    0x54 => Cmp("CMP", [rx, ry], |cpu, rx, ry| check_r(cpu, rx, ry, sub)),
+    0x60 => Andi("ANDI", [rx, hhll], |cpu, rx, hhll| math(cpu, rx, hhll, |_, a, b| a & b)),
+    0x61 => And("AND", [rx, ry], |cpu, rx, ry| math_r(cpu, rx, ry, rx, |_, a, b| a & b)),
+    0x62 => And3("AND", [rx, ry, rz], |cpu, rx, ry, rz| math_r(cpu, rx, ry, rz, |_, a, b| a & b)),
+    0x63 => Tsti("TSTI", [rx, hhll], |cpu, rx, hhll| check(cpu, rx, hhll, |_, a, b| a & b)),
+    0x64 => Tst("TST", [rx, ry], |cpu, rx, ry| check_r(cpu, rx, ry, |_, a, b| a & b)),
+    0x70 => Ori("ORI", [rx, hhll], |cpu, rx, hhll| math(cpu, rx, hhll, |_, a, b| a | b)),
+    0x71 => Or("OR", [rx, ry], |cpu, rx, ry| math_r(cpu, rx, ry, rx, |_, a, b| a | b)),
+    0x72 => Or3("OR", [rx, ry, rz], |cpu, rx, ry, rz| math_r(cpu, rx, ry, rz, |_, a, b| a | b)),
+    0x80 => Xori("XORI", [rx, hhll], |cpu, rx, hhll| math(cpu, rx, hhll, |_, a, b| a ^ b)),
+    0x81 => Xor("XOR", [rx, ry], |cpu, rx, ry| math_r(cpu, rx, ry, rx, |_, a, b| a ^ b)),
+    0x82 => Xor3("XOR", [rx, ry, rz], |cpu, rx, ry, rz| math_r(cpu, rx, ry, rz, |_, a, b| a ^ b)),
+    0x90 => Muli("MULI", [rx, hhll], |cpu, rx, hhll| math(cpu, rx, hhll, mul)),
+    0x91 => Mul("MUL", [rx, ry], |cpu, rx, ry| math_r(cpu, rx, ry, rx, mul)),
+    0x92 => Mul3("MUL", [rx, ry, rz], |cpu, rx, ry, rz| math_r(cpu, rx, ry, rz, mul)),
+    0xA0 => Divi("DIVI", [rx, hhll], |cpu, rx, hhll| division(cpu, rx, hhll, div)),
+    0xA1 => Div("DIV", [rx, ry], |cpu, rx, ry| division_r(cpu, rx, ry, rx, div)),
+    0xA2 => Div3("DIV", [rx, ry, rz], |cpu, rx, ry, rz| division_r(cpu, rx, ry, rz, div)),
+    0xA3 => Modi("MODI", [rx, hhll], |cpu, rx, hhll| division(cpu, rx, hhll, modulo)),
+    0xA4 => Mod("MOD", [rx, ry], |cpu, rx, ry| division_r(cpu, rx, ry, rx, modulo)),
+    0xA5 => Mod3("MOD", [rx, ry, rz], |cpu, rx, ry, rz| division_r(cpu, rx, ry, rz, modulo)),
+    0xA6 => Remi("REMI", [rx, hhll], |cpu, rx, hhll| division(cpu, rx, hhll, rem)),
+    0xA7 => Rem("REM", [rx, ry], |cpu, rx, ry| division_r(cpu, rx, ry, rx, rem)),
+    0xA8 => Rem3("REM", [rx, ry, rz], |cpu, rx, ry, rz| division_r(cpu, rx, ry, rz, rem)),
+    0xB0 => Shl("SHL", [rx, hhll], |cpu, rx, hhll| math(cpu, rx, hhll, shl)),
+    0xB1 => Shr("SHR", [rx, hhll], |cpu, rx, hhll| math(cpu, rx, hhll, shr)),
+    0xB2 => Sar("SAR", [rx, hhll], |cpu, rx, hhll| math(cpu, rx, hhll, sar)),
+    0xB3 => ShlR("SHL", [rx, ry], |cpu, rx, ry| math_r(cpu, rx, ry, rx, shl)),
+    0xB4 => ShrR("SHR", [rx, ry], |cpu, rx, ry| math_r(cpu, rx, ry, rx, shr)),
+    0xB5 => SarR("SAR", [rx, ry], |cpu, rx, ry| math_r(cpu, rx, ry, rx, sar)),
+    0xC0 => Push("PUSH", [rx], push),
+    0xC1 => Pop("POP", [rx], pop),
+    0xC2 => Pushall("PUSHALL", [], pushall),
+    0xC3 => Popall("POPALL", [], popall),
+    0xC4 => Pushf("PUSHF", [], pushf),
+    0xC5 => Popf("POPF", [], popf),
+    0xD0 => Pal("PAL", [hhll], pal),
+    0xD1 => PalR("PAL", [rx], pal_r),
+    0xE0 => Noti("NOTI", [rx, hhll], |cpu, rx, hhll| math(cpu, rx, hhll, |_, _, b| !b)),
+    0xE1 => Not("NOT", [rx], |cpu, rx| math_r(cpu, rx, 0, rx, |_, a, _| !a)),
+    0xE2 => Not2("NOT", [rx, ry], |cpu, rx, ry| math_r(cpu, 0, ry, rx, |_, _, b| !b)),
+    0xE3 => Negi("NEGI", [rx, hhll], |cpu, rx, hhll| math(cpu, rx, hhll, |_, _, b| b.wrapping_neg())),
+    0xE4 => Neg("NEG", [rx], |cpu, rx| math_r(cpu, rx, 0, rx, |_, a, _| a.wrapping_neg())),
+    0xE5 => Neg2("NEG", [rx, ry], |cpu, rx, ry| math_r(cpu, 0, ry, rx, |_, _, b| b.wrapping_neg()))
+}
+
+operands!(rx, ry, rz, hhll, cond, ad, ll, hh);
+
+impl Instruction {
+    /// The registers whose values are used by the instruction
+    pub fn reads(&self) -> Vec<u8> {
+        use Instruction::*;
+        match self {
+            Pushall {} => (0..16).collect(),
+            _ if self.is_load() => self.ry().into_iter().collect(),
+            _ if self.is_math3() => vec![self.rx().unwrap(), self.ry().unwrap()],
+            _ => [self.rx(), self.ry(), self.rz()].iter().flatten().copied().collect(),
+        }
+    }
+
+    /// The registers that are changed by the instruction
+    pub fn writes(&self) -> Vec<u8> {
+        use Instruction::*;
+        match self {
+            Popall {} => (0..16).collect(),
+            _ if self.is_load() || self.is_math() => vec![self.rx().unwrap()],
+            _ if self.is_math3() => vec![self.rz().unwrap()],
+            _ => vec![],
+        }
+    }
+
+    /// Check if the instruction may change the program counter to something else than the next instruction
+    pub fn is_branch(&self) -> bool {
+        use Instruction::*;
+        matches!(self, Jmp { .. } | Jmc { .. } | Jx { .. } | Jme { .. } | Call { .. } | Ret {} | JmpR { .. } | Cx { .. } | CallR { .. })
+    }
+
+    /// Check if the instruction reads or writes the memory, including the stack, sprites, palettes and sounds
+    pub fn touches_memory(&self) -> bool {
+        use Instruction::*;
+        matches!(self, Drw { .. } | DrwR { .. } | Snp { .. } | Call { .. } | Ret {} | Cx { .. } | CallR { .. }
+            | Ldm { .. } | LdmR { .. } | Stm { .. } | StmR { .. } | Push { .. } | Pop { .. }
+            | Pushall {} | Popall {} | Pushf {} | Popf {} | Pal { .. } | PalR { .. })
+    }
+
+    /// Instructions that set `rx` without reading it
+    fn is_load(&self) -> bool {
+        use Instruction::*;
+        matches!(self, Rnd { .. } | Ldi { .. } | Ldm { .. } | LdmR { .. } | Mov { .. } | Pop { .. }
+            | Noti { .. } | Not2 { .. } | Negi { .. } | Neg2 { .. })
+    }
+
+    /// Arithmetic and logic instructions that store the result in `rx`
+    fn is_math(&self) -> bool {
+        use Instruction::*;
+        matches!(self, Addi { .. } | Add { .. } | Subi { .. } | Sub { .. } | Andi { .. } | And { .. }
+            | Ori { .. } | Or { .. } | Xori { .. } | Xor { .. } | Muli { .. } | Mul { .. }
+            | Divi { .. } | Div { .. } | Modi { .. } | Mod { .. } | Remi { .. } | Rem { .. }
+            | Shl { .. } | Shr { .. } | Sar { .. } | ShlR { .. } | ShrR { .. } | SarR { .. } | Not { .. } | Neg { .. })
+    }
+
+    /// Arithmetic and logic instructions that store the result in `rz`
+    fn is_math3(&self) -> bool {
+        use Instruction::*;
+        matches!(self, Add3 { .. } | Sub3 { .. } | And3 { .. } | Or3 { .. } | Xor3 { .. }
+            | Mul3 { .. } | Div3 { .. } | Mod3 { .. } | Rem3 { .. })
+    }
+}
+
+/// Test a given condition and return the result, unknown conditions are false
+fn condition(cpu: &mut Cpu, cond: u8) -> bool {
     match cond {
         0x0 => cpu.zero(), // Equal
         0x1 => !cpu.zero(), // Not equal
@@ -187,7 +328,10 @@ fn condition(cpu: &Cpu, cond: u8) -> bool {
         0xC => cpu.overflow() == cpu.negative(), // Signed greater than equal
         0xD => cpu.overflow() != cpu.negative(), // Signed less than
         0xE => cpu.overflow() != cpu.negative() || cpu.zero(), // Signed less Than equal
-        _ => false
+        _ => {
+            cpu.undefined(|| format!("unknown condition 0x{:X}", cond));
+            false
+        }
     }
 }
 
@@ -212,6 +356,38 @@ fn math_r<F: Fn(&mut Cpu, i16, i16) -> i16>(cpu: &mut Cpu, rx: u8, ry: u8, rz: u
     cpu.set_r(rz, res);
 }
 
+/// Perform a division like `math`, dividing by 0 follows the policy of the cpu
+fn division<F: Fn(&mut Cpu, i16, i16) -> i16>(cpu: &mut Cpu, rx: u8, hhll: u16, f: F) {
+    if hhll == 0 {
+        div_zero(cpu, rx);
+    } else {
+        math(cpu, rx, hhll, f);
+    }
+}
+
+/// Perform a division like `math_r`, dividing by 0 follows the policy of the cpu
+fn division_r<F: Fn(&mut Cpu, i16, i16) -> i16>(cpu: &mut Cpu, rx: u8, ry: u8, rz: u8, f: F) {
+    if cpu.r(ry) == 0 {
+        div_zero(cpu, rz);
+    } else {
+        math_r(cpu, rx, ry, rz, f);
+    }
+}
+
+/// Handle a division by 0 with the result register `rz`
+fn div_zero(cpu: &mut Cpu, rz: u8) {
+    match cpu.div_zero {
+        DivZero::Trap => return cpu.trap("division by zero".into()),
+        DivZero::Zero => cpu.regs.flags = 0,
+        DivZero::Carry => {
+            cpu.regs.flags = 0;
+            cpu.set_carry(true);
+        },
+    }
+    cpu.set_r(rz, 0);
+    cpu.set_zero(true);
+}
+
 /// Perform a given math operation and discard the result (e.g. `cmpi`)
 fn check<F: Fn(&mut Cpu, i16, i16) -> i16>(cpu: &mut Cpu, rx: u8, hhll: u16, f: F) {
     cpu.regs.flags = 0;
@@ -255,17 +431,43 @@ fn mul(cpu: &mut Cpu, a: i16, b: i16) -> i16 {
 fn div(cpu: &mut Cpu, a: i16, b: i16) -> i16 {
     let (res, o) = a.overflowing_div(b);
     cpu.set_overflow(o);
-    cpu.set_carry(a % b > 0);
+    cpu.set_carry(a.wrapping_rem(b) != 0);
     res
 }
 
+/// The remainder of a division with the sign of the divisor
+fn modulo(_: &mut Cpu, a: i16, b: i16) -> i16 {
+    let res = a.wrapping_rem(b);
+    if res != 0 && (res < 0) != (b < 0) { res + b } else { res }
+}
+
+/// The remainder of a division with the sign of the dividend
+fn rem(_: &mut Cpu, a: i16, b: i16) -> i16 {
+    a.wrapping_rem(b)
+}
+
+/// Shift left, shifting by 16 or more bits clears the value
+fn shl(_: &mut Cpu, a: i16, b: i16) -> i16 {
+    (a as u16).checked_shl(b as u16 as u32).unwrap_or(0) as i16
+}
+
+/// Shift right logically, shifting by 16 or more bits clears the value
+fn shr(_: &mut Cpu, a: i16, b: i16) -> i16 {
+    (a as u16).checked_shr(b as u16 as u32).unwrap_or(0) as i16
+}
+
+/// Shift right arithmetically, shifting by 16 or more bits fills the value with the sign
+fn sar(_: &mut Cpu, a: i16, b: i16) -> i16 {
+    a >> (b as u16).min(15)
+}
+
 fn cls(cpu: &mut Cpu) {
     cpu.gpu.clear();
 }
 
 fn vblnk(cpu: &mut Cpu) {
     if !cpu.gpu.vblank() {
-        cpu.regs.pc -= 4;
+        cpu.regs.pc = cpu.regs.pc.wrapping_sub(4);
         cpu.wait_vblank = true;
     }
 }
@@ -281,10 +483,12 @@ fn spr(cpu: &mut Cpu, ll: u8, hh: u8) {
 fn drw(cpu: &mut Cpu, rx: u8, ry: u8, hhll: u16) {
     let x = cpu.r(rx);
     let y = cpu.r(ry);
+    let (w, h) = cpu.gpu.sprite_size();
     let overlap = {
-        let sprite = &cpu.memory.as_slice()[hhll as usize..];
-        cpu.gpu.draw(x, y, sprite)
+        let sprite = wrapping_slice(&cpu.memory, hhll, w as usize * h as usize);
+        cpu.gpu.draw(x, y, &sprite)
     };
+    cpu.gpu.log_draw(hhll, x, y, overlap);
     cpu.set_carry(overlap);
 }
 
@@ -299,8 +503,11 @@ fn rnd(cpu: &mut Cpu, rx: u8, hhll: u16) {
 }
 
 fn flip(cpu: &mut Cpu, n: u8) {
+    if n > 3 {
+        cpu.undefined(|| format!("FLIP {}", n));
+    }
     cpu.gpu.set_hflip(n > 1);
-    cpu.gpu.set_vflip(n % 2 > 0);
+    cpu.gpu.set_vflip(n & 1 != 0);
 }
 
 fn snd0(cpu: &mut Cpu) {
@@ -329,6 +536,9 @@ fn sng(cpu: &mut Cpu, ad: u8, sr: u8, vt: u8) {
     let (a, d) = half_bytes(ad);
     let (s, r) = half_bytes(sr);
     let (v, t) = half_bytes(vt);
+    if t > 3 {
+        cpu.undefined(|| format!("unknown wave type {}", t));
+    }
     cpu.apu.settings(a, d, s, r, v, t);
 }
 
@@ -350,15 +560,12 @@ fn jme(cpu: &mut Cpu, rx: u8, ry: u8, hhll: u16) {
 
 fn call(cpu: &mut Cpu, hhll: u16) {
     let pc = cpu.regs.pc;
-    let sp = cpu.regs.sp;
-    cpu.write(sp, pc as i16);
-    cpu.regs.sp += 2;
+    push_value(cpu, pc as i16);
     cpu.regs.pc = hhll;
 }
 
 fn ret(cpu: &mut Cpu) {
-    cpu.regs.sp -= 2;
-    cpu.regs.pc = cpu.read(cpu.regs.sp);
+    cpu.regs.pc = pop_value(cpu);
 }
 
 fn jmp_r(cpu: &mut Cpu, rx: u8) {
@@ -410,17 +617,44 @@ fn stm_r(cpu: &mut Cpu, rx: u8, ry: u8) {
     stm(cpu, rx, addr);
 }
 
-fn push(cpu: &mut Cpu, rx: u8) {
+/// Push a value onto the stack
+fn push_value(cpu: &mut Cpu, val: i16) {
     let sp = cpu.regs.sp;
-    let val = cpu.r(rx);
+    guard_stack(cpu, sp);
     cpu.write(sp, val);
-    cpu.regs.sp += 2;
+    cpu.regs.sp = sp.wrapping_add(2);
 }
 
-fn pop(cpu: &mut Cpu, rx: u8) {
-    cpu.regs.sp -= 2;
+/// Pop a value from the stack
+fn pop_value<T: Copy>(cpu: &mut Cpu) -> T {
+    let sp = cpu.regs.sp.wrapping_sub(2);
+    guard_stack(cpu, sp);
+    cpu.regs.sp = sp;
+    cpu.read(sp)
+}
+
+/// Raise a trap if the stack guard is enabled and `addr` is misaligned or outside of the stack
+fn guard_stack(cpu: &mut Cpu, addr: u16) {
+    if !cpu.stack_guard {
+        return;
+    }
     let sp = cpu.regs.sp;
-    let val = cpu.read(sp);
+    if addr & 1 != 0 {
+        cpu.trap(format!("misaligned stack pointer 0x{:04X}", sp));
+    } else if addr >= STACK_END {
+        cpu.trap(format!("stack overflow (SP 0x{:04X})", sp));
+    } else if addr < STACK_START {
+        cpu.trap(format!("stack underflow (SP 0x{:04X})", sp));
+    }
+}
+
+fn push(cpu: &mut Cpu, rx: u8) {
+    let val = cpu.r(rx);
+    push_value(cpu, val);
+}
+
+fn pop(cpu: &mut Cpu, rx: u8) {
+    let val = pop_value(cpu);
     cpu.set_r(rx, val);
 }
 
@@ -437,29 +671,784 @@ fn popall(cpu: &mut Cpu) {
 }
 
 fn pushf(cpu: &mut Cpu) {
-    let sp = cpu.regs.sp;
     let val = cpu.regs.flags;
-    cpu.write(sp, val as i16);
-    cpu.regs.sp += 2;
+    push_value(cpu, val as i16);
 }
 
 fn popf(cpu: &mut Cpu) {
-    cpu.regs.sp -= 2;
-    let sp = cpu.regs.sp;
-    let val = cpu.read(sp);
-    cpu.regs.flags = val;
+    cpu.regs.flags = pop_value(cpu);
 }
 
 fn pal(cpu: &mut Cpu, hhll: u16) {
     let mut palette = [0; 16];
-    let m = &cpu.memory.as_slice()[hhll as usize..];
+    let m = wrapping_slice(&cpu.memory, hhll, 48);
     for i in 0..16 {
         palette[i] = (m[i * 3] as u32) << 16 | (m[i * 3 + 1] as u32) << 8 | m[i * 3 + 2] as u32;
     }
-    cpu.gpu.set_palette(palette);
+    cpu.gpu.load_palette(hhll, palette);
 }
 
 fn pal_r(cpu: &mut Cpu, rx: u8) {
     let addr = cpu.r(rx) as u16;
     pal(cpu, addr);
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::apu::Apu;
+    use crate::cpu::{Strict, Trap};
+    use crate::cpu::instructions::*;
+    use crate::gpu::Gpu;
+    use crate::rom::RomBuilder;
+    use rand::rngs::SmallRng;
+    use rand::{Rng, SeedableRng};
+
+    fn new_cpu() -> Cpu {
+        let mut cpu = Cpu::new(Gpu::new(), Apu::silent(1.0), &RomBuilder::new(vec![0; 4]).build());
+        cpu.set_seed(0);
+        cpu
+    }
+
+    /// Write an instruction to the program counter and execute it
+    fn exec(cpu: &mut Cpu, op: [u8; 4]) {
+        let pc = cpu.pc();
+        cpu.memory[pc as usize..pc as usize + 4].copy_from_slice(&op);
+        cpu.invalidate(pc);
+        cpu.step();
+    }
+
+    /// The flags in the order carry, zero, overflow, negative, e.g. `C--N`
+    fn flags(cpu: &Cpu) -> &'static str {
+        static FLAGS: [&str; 16] = [
+            "----", "C---", "-Z--", "CZ--", "--O-", "C-O-", "-ZO-", "CZO-",
+            "---N", "C--N", "-Z-N", "CZ-N", "--ON", "C-ON", "-ZON", "CZON",
+        ];
+        let bits = [cpu.carry(), cpu.zero(), cpu.overflow(), cpu.negative()];
+        FLAGS[bits.iter().rev().fold(0, |acc, &bit| acc << 1 | bit as usize)]
+    }
+
+    fn imm(op: u8, a: i16, b: i16) -> (i16, &'static str) {
+        let mut cpu = new_cpu();
+        cpu.set_r(1, a);
+        let [ll, hh] = (b as u16).to_le_bytes();
+        exec(&mut cpu, [op, 0x01, ll, hh]);
+        (cpu.r(1), flags(&cpu))
+    }
+
+    fn reg2(op: u8, a: i16, b: i16) -> (i16, &'static str) {
+        let mut cpu = new_cpu();
+        cpu.set_r(1, a);
+        cpu.set_r(2, b);
+        exec(&mut cpu, [op, 0x21, 0, 0]);
+        assert_eq!(cpu.r(2), b);
+        (cpu.r(1), flags(&cpu))
+    }
+
+    fn reg3(op: u8, a: i16, b: i16) -> (i16, &'static str) {
+        let mut cpu = new_cpu();
+        cpu.set_r(1, a);
+        cpu.set_r(2, b);
+        exec(&mut cpu, [op, 0x21, 0x03, 0]);
+        assert_eq!((cpu.r(1), cpu.r(2)), (a, b));
+        (cpu.r(3), flags(&cpu))
+    }
+
+    /// Run the immediate, two and three register forms of an operation,
+    /// check that they agree and return the result and the flags
+    fn alu(op: u8, a: i16, b: i16) -> (i16, &'static str) {
+        let res = imm(op, a, b);
+        assert_eq!(reg2(op + 1, a, b), res, "0x{:02X} {} {}", op + 1, a, b);
+        assert_eq!(reg3(op + 2, a, b), res, "0x{:02X} {} {}", op + 2, a, b);
+        res
+    }
+
+    /// Run the immediate and register forms of a shift
+    fn shift(op: u8, a: i16, n: i16) -> (i16, &'static str) {
+        let res = imm(op, a, n);
+        assert_eq!(reg2(op + 3, a, n), res, "0x{:02X} {} {}", op + 3, a, n);
+        res
+    }
+
+    /// Run the immediate and register forms of a comparison, which keep the register
+    fn compare(op: u8, a: i16, b: i16) -> &'static str {
+        let res = imm(op, a, b);
+        assert_eq!(reg2(op + 1, a, b), res);
+        assert_eq!(res.0, a);
+        res.1
+    }
+
+    #[test]
+    fn add_works() {
+        assert_eq!(alu(0x40, 1, 2), (3, "----"));
+        assert_eq!(alu(0x40, 0x7FFF, 1), (-0x8000, "--ON"));
+        assert_eq!(alu(0x40, -1, 1), (0, "CZ--"));
+        assert_eq!(alu(0x40, -0x8000, -0x8000), (0, "CZO-"));
+        assert_eq!(alu(0x40, -1, -1), (-2, "C--N"));
+        assert_eq!(alu(0x40, -3, 1), (-2, "---N"));
+    }
+
+    #[test]
+    fn sub_works() {
+        assert_eq!(alu(0x50, 5, 3), (2, "----"));
+        assert_eq!(alu(0x50, 7, 7), (0, "-Z--"));
+        assert_eq!(alu(0x50, 3, 5), (-2, "C--N"));
+        assert_eq!(alu(0x50, -0x8000, 1), (0x7FFF, "--O-"));
+        assert_eq!(alu(0x50, 0, -0x8000), (-0x8000, "C-ON"));
+        assert_eq!(alu(0x50, -1, 1), (-2, "---N"));
+    }
+
+    #[test]
+    fn cmp_works() {
+        assert_eq!(compare(0x53, 5, 3), "----");
+        assert_eq!(compare(0x53, 7, 7), "-Z--");
+        assert_eq!(compare(0x53, 3, 5), "C--N");
+        assert_eq!(compare(0x53, -0x8000, 1), "--O-");
+        assert_eq!(compare(0x53, 0, -0x8000), "C-ON");
+    }
+
+    #[test]
+    fn mul_works() {
+        assert_eq!(alu(0x90, 3, 4), (12, "----"));
+        assert_eq!(alu(0x90, 0, 1234), (0, "-Z--"));
+        assert_eq!(alu(0x90, 256, 256), (0, "CZO-"));
+        assert_eq!(alu(0x90, -1, 1), (-1, "---N"));
+        assert_eq!(alu(0x90, -2, 3), (-6, "C--N"));
+        assert_eq!(alu(0x90, 200, 200), (-25536, "--ON"));
+        assert_eq!(alu(0x90, -0x8000, -1), (-0x8000, "C-ON"));
+    }
+
+    #[test]
+    fn div_works() {
+        assert_eq!(alu(0xA0, 6, 3), (2, "----"));
+        assert_eq!(alu(0xA0, 7, 2), (3, "C---"));
+        assert_eq!(alu(0xA0, 1, 2), (0, "CZ--"));
+        assert_eq!(alu(0xA0, -7, 2), (-3, "C--N"));
+        assert_eq!(alu(0xA0, 7, -2), (-3, "C--N"));
+        assert_eq!(alu(0xA0, -6, -3), (2, "----"));
+        assert_eq!(alu(0xA0, -0x8000, -1), (-0x8000, "--ON"));
+    }
+
+    #[test]
+    fn mod_has_sign_of_divisor() {
+        assert_eq!(alu(0xA3, 7, 3), (1, "----"));
+        assert_eq!(alu(0xA3, -7, 3), (2, "----"));
+        assert_eq!(alu(0xA3, 7, -3), (-2, "---N"));
+        assert_eq!(alu(0xA3, -7, -3), (-1, "---N"));
+        assert_eq!(alu(0xA3, 6, -3), (0, "-Z--"));
+        assert_eq!(alu(0xA3, -1, -0x8000), (-1, "---N"));
+        assert_eq!(alu(0xA3, -0x8000, 0x7FFF), (0x7FFE, "----"));
+        assert_eq!(alu(0xA3, -0x8000, -1), (0, "-Z--"));
+    }
+
+    #[test]
+    fn rem_has_sign_of_dividend() {
+        assert_eq!(alu(0xA6, 7, 3), (1, "----"));
+        assert_eq!(alu(0xA6, -7, 3), (-1, "---N"));
+        assert_eq!(alu(0xA6, 7, -3), (1, "----"));
+        assert_eq!(alu(0xA6, -7, -3), (-1, "---N"));
+        assert_eq!(alu(0xA6, 6, -3), (0, "-Z--"));
+        assert_eq!(alu(0xA6, -1, -0x8000), (-1, "---N"));
+        assert_eq!(alu(0xA6, -0x8000, 0x7FFF), (-1, "---N"));
+        assert_eq!(alu(0xA6, -0x8000, -1), (0, "-Z--"));
+    }
+
+    #[test]
+    fn division_by_zero_follows_policy() {
+        let policies = [(DivZero::Trap, 9, "C-ON"), (DivZero::Zero, 0, "-Z--"), (DivZero::Carry, 0, "CZ--")];
+        for op in 0xA0..=0xA8 {
+            for &(policy, res, expected) in &policies {
+                let mut cpu = new_cpu();
+                cpu.set_div_zero(policy);
+                // Flags left by an earlier instruction are cleared unless the division traps
+                cpu.set_carry(true);
+                cpu.set_overflow(true);
+                cpu.set_negative(true);
+                cpu.set_r(1, 9);
+                cpu.set_r(3, 9);
+                // The immediate, two and three register forms with the result in R1, R1 and R3
+                let (instr, rz) = match (op - 0xA0) % 3 {
+                    0 => ([op, 0x01, 0, 0], 1),
+                    1 => ([op, 0x21, 0, 0], 1),
+                    _ => ([op, 0x21, 0x03, 0], 3),
+                };
+                exec(&mut cpu, instr);
+
+                assert_eq!((cpu.r(rz), flags(&cpu)), (res, expected), "0x{:02X} {:?}", op, policy);
+                assert_eq!(cpu.pc(), 4);
+                let trap = cpu.take_trap();
+                if policy == DivZero::Trap {
+                    assert_eq!(trap, Some(Trap { pc: 0, reason: "division by zero".into() }));
+                } else {
+                    assert_eq!(trap, None);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn logic_works() {
+        assert_eq!(alu(0x60, 0b1100, 0b1010), (0b1000, "----"));
+        assert_eq!(alu(0x60, 0b0101, 0b1010), (0, "-Z--"));
+        assert_eq!(alu(0x60, -1, -0x8000), (-0x8000, "---N"));
+        assert_eq!(alu(0x70, 0b1100, 0b1010), (0b1110, "----"));
+        assert_eq!(alu(0x70, 0, 0), (0, "-Z--"));
+        assert_eq!(alu(0x70, 1, -0x8000), (-0x7FFF, "---N"));
+        assert_eq!(alu(0x80, 0b1100, 0b1010), (0b0110, "----"));
+        assert_eq!(alu(0x80, -1, -1), (0, "-Z--"));
+        assert_eq!(alu(0x80, 0x7FFF, -1), (-0x8000, "---N"));
+    }
+
+    #[test]
+    fn tst_works() {
+        assert_eq!(compare(0x63, 0b1100, 0b1010), "----");
+        assert_eq!(compare(0x63, 0b0101, 0b1010), "-Z--");
+        assert_eq!(compare(0x63, -1, -0x8000), "---N");
+    }
+
+    #[test]
+    fn logic_clears_carry_and_overflow() {
+        let mut cpu = new_cpu();
+        cpu.set_carry(true);
+        cpu.set_overflow(true);
+        exec(&mut cpu, [0x70, 0x01, 0x01, 0x00]);
+        assert_eq!(flags(&cpu), "----");
+    }
+
+    #[test]
+    fn shifts_work() {
+        assert_eq!(shift(0xB0, 1, 4), (16, "----"));
+        assert_eq!(shift(0xB0, 1, 15), (-0x8000, "---N"));
+        assert_eq!(shift(0xB0, -0x8000, 1), (0, "-Z--"));
+        assert_eq!(shift(0xB1, -0x8000, 15), (1, "----"));
+        assert_eq!(shift(0xB1, -1, 4), (0x0FFF, "----"));
+        assert_eq!(shift(0xB2, -0x8000, 15), (-1, "---N"));
+        assert_eq!(shift(0xB2, 0x4000, 14), (1, "----"));
+        assert_eq!(shift(0xB2, -16, 2), (-4, "---N"));
+    }
+
+    #[test]
+    fn shifts_by_16_or_more_saturate() {
+        for &n in &[16, 17, 31, 0x7FFF, -1] {
+            assert_eq!(shift(0xB0, 1, n), (0, "-Z--"), "SHL {}", n);
+            assert_eq!(shift(0xB1, -1, n), (0, "-Z--"), "SHR {}", n);
+            assert_eq!(shift(0xB2, -0x8000, n), (-1, "---N"), "SAR {}", n);
+            assert_eq!(shift(0xB2, 0x7FFF, n), (0, "-Z--"), "SAR {}", n);
+        }
+    }
+
+    #[test]
+    fn not_works() {
+        assert_eq!(imm(0xE0, 5, 0x00FF), (-256, "---N"));
+        assert_eq!(imm(0xE0, 5, -1), (0, "-Z--"));
+        assert_eq!(imm(0xE1, 0, 0), (-1, "---N"));
+        assert_eq!(imm(0xE1, -0x8000, 0), (0x7FFF, "----"));
+        assert_eq!(reg2(0xE2, 5, 0x00FF), (-256, "---N"));
+    }
+
+    #[test]
+    fn neg_works() {
+        assert_eq!(imm(0xE3, 0, 5), (-5, "---N"));
+        assert_eq!(imm(0xE3, 0, -0x8000), (-0x8000, "---N"));
+        assert_eq!(imm(0xE4, 7, 0), (-7, "---N"));
+        assert_eq!(imm(0xE4, 0, 0), (0, "-Z--"));
+        assert_eq!(imm(0xE4, -0x8000, 0), (-0x8000, "---N"));
+        assert_eq!(reg2(0xE5, 0, -0x8000), (-0x8000, "---N"));
+        assert_eq!(reg2(0xE5, 0, -3), (3, "----"));
+    }
+
+    #[test]
+    fn conditions_match_comparisons() {
+        let values: [i16; 9] = [-0x8000, -0x7FFF, -2, -1, 0, 1, 2, 0x7FFE, 0x7FFF];
+        for &a in &values {
+            for &b in &values {
+                let (diff, overflow) = a.overflowing_sub(b);
+                let expected = [
+                    a == b, a != b, diff < 0, diff >= 0, diff > 0, overflow, !overflow,
+                    (a as u16) > (b as u16), (a as u16) >= (b as u16),
+                    (a as u16) < (b as u16), (a as u16) <= (b as u16),
+                    a > b, a >= b, a < b, a <= b, false,
+                ];
+                for (cond, &jump) in expected.iter().enumerate() {
+                    let mut cpu = new_cpu();
+                    cpu.set_r(1, a);
+                    cpu.set_r(2, b);
+                    exec(&mut cpu, [0x54, 0x21, 0, 0]);
+                    exec(&mut cpu, [0x12, cond as u8, 0x00, 0x10]);
+                    let target = if jump { 0x1000 } else { 8 };
+                    assert_eq!(cpu.pc(), target, "J{} after CMP {}, {}", cond, a, b);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn jumps_work() {
+        let mut cpu = new_cpu();
+        exec(&mut cpu, [0x10, 0x00, 0x34, 0x12]);
+        assert_eq!(cpu.pc(), 0x1234);
+
+        let mut cpu = new_cpu();
+        exec(&mut cpu, [0x11, 0x00, 0x00, 0x10]);
+        assert_eq!(cpu.pc(), 4);
+        cpu.set_carry(true);
+        exec(&mut cpu, [0x11, 0x00, 0x00, 0x10]);
+        assert_eq!(cpu.pc(), 0x1000);
+
+        let mut cpu = new_cpu();
+        cpu.set_r(1, 5);
+        cpu.set_r(2, 6);
+        exec(&mut cpu, [0x13, 0x21, 0x00, 0x10]);
+        assert_eq!(cpu.pc(), 4);
+        cpu.set_r(2, 5);
+        exec(&mut cpu, [0x13, 0x21, 0x00, 0x10]);
+        assert_eq!(cpu.pc(), 0x1000);
+
+        let mut cpu = new_cpu();
+        cpu.set_r(3, 0x2000);
+        exec(&mut cpu, [0x16, 0x03, 0x00, 0x00]);
+        assert_eq!(cpu.pc(), 0x2000);
+    }
+
+    #[test]
+    fn calls_work() {
+        let mut cpu = new_cpu();
+        exec(&mut cpu, [0x14, 0x00, 0x00, 0x10]);
+        assert_eq!((cpu.pc(), cpu.sp()), (0x1000, 0xFDF2));
+        assert_eq!(cpu.read::<u16>(0xFDF0), 4);
+        exec(&mut cpu, [0x15, 0x00, 0x00, 0x00]);
+        assert_eq!((cpu.pc(), cpu.sp()), (4, 0xFDF0));
+
+        cpu.set_r(4, 0x2000);
+        exec(&mut cpu, [0x18, 0x04, 0x00, 0x00]);
+        assert_eq!((cpu.pc(), cpu.sp()), (0x2000, 0xFDF2));
+        assert_eq!(cpu.read::<u16>(0xFDF0), 8);
+
+        let mut cpu = new_cpu();
+        exec(&mut cpu, [0x17, 0x00, 0x00, 0x10]);
+        assert_eq!((cpu.pc(), cpu.sp()), (4, 0xFDF0));
+        cpu.set_zero(true);
+        exec(&mut cpu, [0x17, 0x00, 0x00, 0x10]);
+        assert_eq!((cpu.pc(), cpu.sp()), (0x1000, 0xFDF2));
+        assert_eq!(cpu.read::<u16>(0xFDF0), 8);
+    }
+
+    #[test]
+    fn loads_and_stores_work() {
+        let mut cpu = new_cpu();
+        exec(&mut cpu, [0x20, 0x01, 0x34, 0x12]);
+        assert_eq!(cpu.r(1), 0x1234);
+        exec(&mut cpu, [0x21, 0x00, 0x00, 0xFA]);
+        assert_eq!(cpu.sp(), 0xFA00);
+
+        cpu.memory[0x3000] = 0xCD;
+        cpu.memory[0x3001] = 0xAB;
+        exec(&mut cpu, [0x22, 0x02, 0x00, 0x30]);
+        assert_eq!(cpu.r(2) as u16, 0xABCD);
+        cpu.set_r(3, 0x3001);
+        exec(&mut cpu, [0x23, 0x34, 0x00, 0x00]);
+        assert_eq!(cpu.r(4) as u16, 0x00AB);
+
+        exec(&mut cpu, [0x24, 0x15, 0x00, 0x00]);
+        assert_eq!(cpu.r(5), 0x1234);
+
+        exec(&mut cpu, [0x30, 0x01, 0x00, 0x40]);
+        assert_eq!(&cpu.memory[0x4000..0x4002], &[0x34, 0x12]);
+        cpu.set_r(6, 0x4010);
+        exec(&mut cpu, [0x31, 0x62, 0x00, 0x00]);
+        assert_eq!(&cpu.memory[0x4010..0x4012], &[0xCD, 0xAB]);
+        assert_eq!(flags(&cpu), "----");
+    }
+
+    #[test]
+    fn stack_works() {
+        let mut cpu = new_cpu();
+        cpu.set_r(1, 0x1234);
+        exec(&mut cpu, [0xC0, 0x01, 0x00, 0x00]);
+        assert_eq!(cpu.sp(), 0xFDF2);
+        assert_eq!(cpu.read::<i16>(0xFDF0), 0x1234);
+        exec(&mut cpu, [0xC1, 0x02, 0x00, 0x00]);
+        assert_eq!((cpu.sp(), cpu.r(2)), (0xFDF0, 0x1234));
+
+        for i in 0..16 {
+            cpu.set_r(i, i as i16 * 3);
+        }
+        exec(&mut cpu, [0xC2, 0x00, 0x00, 0x00]);
+        assert_eq!(cpu.sp(), 0xFDF0 + 32);
+        assert_eq!(cpu.read::<i16>(0xFDF0 + 2 * 15), 45);
+        for i in 0..16 {
+            cpu.set_r(i, 0);
+        }
+        exec(&mut cpu, [0xC3, 0x00, 0x00, 0x00]);
+        assert_eq!(cpu.sp(), 0xFDF0);
+        assert!((0..16).all(|i| cpu.r(i) == i as i16 * 3));
+
+        cpu.set_carry(true);
+        cpu.set_negative(true);
+        exec(&mut cpu, [0xC4, 0x00, 0x00, 0x00]);
+        assert_eq!(cpu.sp(), 0xFDF2);
+        cpu.regs.flags = 0;
+        exec(&mut cpu, [0xC5, 0x00, 0x00, 0x00]);
+        assert_eq!((cpu.sp(), flags(&cpu)), (0xFDF0, "C--N"));
+    }
+
+    #[test]
+    fn stack_guard_reports_errors() {
+        let mut cpu = new_cpu();
+        cpu.regs.pc = 0x100;
+        exec(&mut cpu, [0xC1, 0x01, 0x00, 0x00]);
+        assert_eq!(cpu.take_trap(), None);
+
+        cpu.set_stack_guard(true);
+        cpu.regs.sp = 0xFFEE;
+        exec(&mut cpu, [0xC0, 0x01, 0x00, 0x00]);
+        assert_eq!(cpu.take_trap(), None);
+        exec(&mut cpu, [0xC4, 0x00, 0x00, 0x00]);
+        assert_eq!(cpu.take_trap(), Some(Trap { pc: 0x108, reason: "stack overflow (SP 0xFFF0)".into() }));
+
+        cpu.regs.sp = 0xFDF0;
+        exec(&mut cpu, [0x15, 0x00, 0x00, 0x00]);
+        assert_eq!(cpu.take_trap(), Some(Trap { pc: 0x10C, reason: "stack underflow (SP 0xFDF0)".into() }));
+
+        cpu.regs.pc = 0x100;
+        cpu.regs.sp = 0xFDF1;
+        exec(&mut cpu, [0xC2, 0x00, 0x00, 0x00]);
+        assert_eq!(cpu.take_trap(), Some(Trap { pc: 0x100, reason: "misaligned stack pointer 0xFDF1".into() }));
+
+        // Unbounded recursion overflows after filling the 512 bytes of the stack
+        cpu.regs.pc = 0x100;
+        cpu.regs.sp = 0xFDF0;
+        cpu.memory[0x100..0x104].copy_from_slice(&[0x14, 0x00, 0x00, 0x01]);
+        cpu.invalidate(0x100);
+        for _ in 0..256 {
+            cpu.step();
+            assert_eq!(cpu.take_trap(), None);
+        }
+        cpu.step();
+        assert_eq!(cpu.take_trap(), Some(Trap { pc: 0x100, reason: "stack overflow (SP 0xFFF0)".into() }));
+    }
+
+    #[test]
+    fn strict_mode_reports_undefined_behaviour() {
+        let mut cpu = new_cpu();
+        exec(&mut cpu, [0x12, 0x0F, 0x00, 0x00]);
+        assert!(cpu.take_warnings().is_empty());
+
+        cpu.set_strict(Strict::Warn);
+        exec(&mut cpu, [0x12, 0x0F, 0x00, 0x00]);
+        exec(&mut cpu, [0x17, 0x1F, 0x00, 0x00]);
+        exec(&mut cpu, [0x08, 0x00, 0x00, 0x04]);
+        exec(&mut cpu, [0x0E, 0x00, 0x00, 0x05]);
+        // The rom is 4 bytes long, so writing to 0x0003 touches its last byte
+        exec(&mut cpu, [0x30, 0x01, 0x03, 0x00]);
+        exec(&mut cpu, [0x30, 0x01, 0x04, 0x00]);
+        let warnings: Vec<String> = cpu.take_warnings().iter().map(|w| w.to_string()).collect();
+        assert_eq!(warnings, [
+            "unknown condition 0xF at PC 0x0004",
+            "unknown condition 0x1F at PC 0x0008",
+            "FLIP 4 at PC 0x000C",
+            "unknown wave type 5 at PC 0x0010",
+            "write to the rom at 0x0003 at PC 0x0014",
+        ]);
+        assert_eq!(cpu.pc(), 0x1C);
+
+        // Warnings are only reported once per address
+        cpu.regs.pc = 0x4;
+        exec(&mut cpu, [0x12, 0x0F, 0x00, 0x00]);
+        assert!(cpu.take_warnings().is_empty());
+
+        cpu.set_strict(Strict::Trap);
+        cpu.regs.pc = 0x4;
+        exec(&mut cpu, [0x12, 0x0F, 0x00, 0x00]);
+        assert_eq!(cpu.take_trap(), Some(Trap { pc: 0x4, reason: "unknown condition 0xF".into() }));
+        assert!(cpu.take_warnings().is_empty());
+    }
+
+    #[test]
+    fn graphics_work() {
+        let mut cpu = new_cpu();
+        exec(&mut cpu, [0x03, 0x00, 0x05, 0x00]);
+        assert!(cpu.gpu().frame().iter().all(|&p| p == 5));
+
+        // A 2x1 sprite at 0x3000 drawn at (10, 20)
+        cpu.memory[0x3000..0x3002].copy_from_slice(&[0x12, 0x30]);
+        exec(&mut cpu, [0x04, 0x00, 0x02, 0x01]);
+        cpu.set_r(1, 10);
+        cpu.set_r(2, 20);
+        exec(&mut cpu, [0x05, 0x21, 0x00, 0x30]);
+        assert!(!cpu.carry());
+        let row = &cpu.gpu().frame()[20 * 320 + 10..20 * 320 + 14];
+        assert_eq!(row, &[1, 2, 3, 5]);
+
+        cpu.set_r(3, 0x3000);
+        exec(&mut cpu, [0x06, 0x21, 0x03, 0x00]);
+        assert!(cpu.carry());
+
+        // Flip horizontally and draw one row lower
+        exec(&mut cpu, [0x08, 0x00, 0x00, 0x02]);
+        cpu.set_r(2, 21);
+        exec(&mut cpu, [0x05, 0x21, 0x00, 0x30]);
+        let row = &cpu.gpu().frame()[21 * 320 + 10..21 * 320 + 14];
+        assert_eq!(row, &[5, 3, 2, 1]);
+
+        exec(&mut cpu, [0x01, 0x00, 0x00, 0x00]);
+        assert!(cpu.gpu().frame().iter().all(|&p| p == 0));
+    }
+
+    #[test]
+    fn vblnk_waits_for_frame() {
+        let mut cpu = new_cpu();
+        exec(&mut cpu, [0x02, 0x00, 0x00, 0x00]);
+        assert!(cpu.wait_vblank());
+        assert_eq!(cpu.pc(), 0);
+
+        cpu.render(&mut vec![0; 320 * 240]);
+        cpu.step();
+        assert!(!cpu.wait_vblank());
+        assert_eq!(cpu.pc(), 4);
+    }
+
+    #[test]
+    fn rnd_is_in_range() {
+        let mut cpu = new_cpu();
+        for _ in 0..100 {
+            exec(&mut cpu, [0x07, 0x01, 0x0A, 0x00]);
+            assert!((0..=10).contains(&cpu.r(1)));
+        }
+        exec(&mut cpu, [0x07, 0x01, 0x00, 0x00]);
+        assert_eq!(cpu.r(1), 0);
+        // The full range must not overflow
+        exec(&mut cpu, [0x07, 0x01, 0xFF, 0xFF]);
+    }
+
+    #[test]
+    fn sound_works() {
+        let mut cpu = new_cpu();
+        exec(&mut cpu, [0x0A, 0x00, 0x64, 0x00]);
+        assert!(cpu.apu_mut().samples(100).iter().any(|&s| s != 0.0));
+        exec(&mut cpu, [0x09, 0x00, 0x00, 0x00]);
+        assert!(cpu.apu_mut().samples(100).iter().all(|&s| s == 0.0));
+
+        for &op in &[0x0B, 0x0C] {
+            exec(&mut cpu, [op, 0x00, 0x64, 0x00]);
+            assert!(cpu.apu_mut().samples(100).iter().any(|&s| s != 0.0));
+            exec(&mut cpu, [0x09, 0x00, 0x00, 0x00]);
+        }
+
+        // Attack 0, decay 0, sustain 15, release 0, volume 15 and a square wave at 440 Hz
+        exec(&mut cpu, [0x0E, 0x00, 0xF0, 0xF1]);
+        cpu.memory[0x3000..0x3002].copy_from_slice(&440u16.to_le_bytes());
+        cpu.set_r(1, 0x3000);
+        exec(&mut cpu, [0x0D, 0x01, 0x64, 0x00]);
+        assert!(cpu.apu_mut().samples(100).iter().any(|&s| s != 0.0));
+    }
+
+    #[test]
+    fn pal_works() {
+        let mut cpu = new_cpu();
+        for i in 0..48 {
+            cpu.memory[0x3000 + i] = i as u8;
+        }
+        exec(&mut cpu, [0xD0, 0x00, 0x00, 0x30]);
+        assert_eq!(cpu.gpu().palette()[1], 0x030405);
+        assert_eq!(cpu.gpu().palette_source(), Some((0x3000, 0)));
+
+        cpu.set_r(1, 0x3003);
+        exec(&mut cpu, [0xD1, 0x01, 0x00, 0x00]);
+        assert_eq!(cpu.gpu().palette()[0], 0x030405);
+        assert_eq!(cpu.gpu().palette_source(), Some((0x3003, 0)));
+    }
+
+    #[test]
+    fn loads_and_stores_wrap_around() {
+        let mut cpu = new_cpu();
+        cpu.regs.pc = 0x100;
+        cpu.write(0xFFFF, 0x1234i16);
+        assert_eq!((cpu.memory[0xFFFF], cpu.memory[0]), (0x34, 0x12));
+        assert_eq!(cpu.read::<i16>(0xFFFF), 0x1234);
+
+        cpu.set_r(1, 0x5678);
+        exec(&mut cpu, [0x30, 0x01, 0xFF, 0xFF]);
+        assert_eq!((cpu.memory[0xFFFF], cpu.memory[0]), (0x78, 0x56));
+        exec(&mut cpu, [0x22, 0x02, 0xFF, 0xFF]);
+        assert_eq!(cpu.r(2), 0x5678);
+        cpu.set_r(3, -1);
+        exec(&mut cpu, [0x23, 0x34, 0x00, 0x00]);
+        assert_eq!(cpu.r(4), 0x5678);
+    }
+
+    #[test]
+    fn fetch_wraps_around() {
+        let mut cpu = new_cpu();
+        // LDI R1, 0x1234 split across the end of the memory
+        cpu.memory[0xFFFE..].copy_from_slice(&[0x20, 0x01]);
+        cpu.memory[..2].copy_from_slice(&[0x34, 0x12]);
+        cpu.regs.pc = 0xFFFE;
+        assert_eq!(cpu.format_instruction(0xFFFE).unwrap(), "LDI R1 4660");
+        cpu.step();
+        assert_eq!((cpu.r(1), cpu.pc()), (0x1234, 2));
+
+        cpu.regs.pc = 0xFFFC;
+        exec(&mut cpu, [0x00, 0x00, 0x00, 0x00]);
+        assert_eq!(cpu.pc(), 0);
+    }
+
+    #[test]
+    fn drw_wraps_around() {
+        let mut cpu = new_cpu();
+        cpu.regs.pc = 0x100;
+        // A 2x2 sprite whose second row is at the start of the memory
+        cpu.memory[0xFFFE..].copy_from_slice(&[0x12, 0x34]);
+        cpu.memory[..2].copy_from_slice(&[0x56, 0x78]);
+        exec(&mut cpu, [0x04, 0x00, 0x02, 0x02]);
+        exec(&mut cpu, [0x05, 0x21, 0xFE, 0xFF]);
+        let frame = cpu.gpu().frame();
+        assert_eq!(&frame[..4], &[1, 2, 3, 4]);
+        assert_eq!(&frame[320..324], &[5, 6, 7, 8]);
+
+        cpu.set_r(3, -2);
+        exec(&mut cpu, [0x06, 0x21, 0x03, 0x00]);
+        assert!(cpu.carry());
+    }
+
+    #[test]
+    fn pal_wraps_around() {
+        let mut cpu = new_cpu();
+        cpu.regs.pc = 0x100;
+        for i in 0..48 {
+            cpu.memory[(0xFFF0 + i) % 0x10000] = i as u8;
+        }
+        exec(&mut cpu, [0xD0, 0x00, 0xF0, 0xFF]);
+        assert_eq!(cpu.gpu().palette()[5], 0x0F1011);
+        assert_eq!(cpu.gpu().palette()[15], 0x2D2E2F);
+    }
+
+    #[test]
+    fn stack_wraps_around() {
+        let mut cpu = new_cpu();
+        cpu.regs.pc = 0x100;
+        cpu.regs.sp = 0xFFFE;
+        cpu.set_r(1, 0x1234);
+        exec(&mut cpu, [0xC0, 0x01, 0x00, 0x00]);
+        assert_eq!(cpu.sp(), 0);
+        assert_eq!(cpu.read::<i16>(0xFFFE), 0x1234);
+        exec(&mut cpu, [0xC1, 0x02, 0x00, 0x00]);
+        assert_eq!((cpu.sp(), cpu.r(2)), (0xFFFE, 0x1234));
+
+        cpu.regs.sp = 0;
+        exec(&mut cpu, [0xC1, 0x02, 0x00, 0x00]);
+        assert_eq!((cpu.sp(), cpu.r(2)), (0xFFFE, 0x1234));
+        exec(&mut cpu, [0x14, 0x00, 0x00, 0x02]);
+        assert_eq!((cpu.sp(), cpu.pc()), (0, 0x200));
+        exec(&mut cpu, [0x15, 0x00, 0x00, 0x00]);
+        assert_eq!((cpu.sp(), cpu.pc()), (0xFFFE, 0x110));
+    }
+
+    #[test]
+    fn decode_cache_is_invalidated_by_writes() {
+        for cache in [true, false] {
+            let mut cpu = new_cpu();
+            cpu.set_decode_cache(cache);
+            cpu.memory[0x100..0x104].copy_from_slice(&[0x20, 0x02, 0x01, 0x00]);
+            cpu.regs.pc = 0x100;
+            cpu.step();
+            assert_eq!(cpu.r(2), 1);
+
+            // Change the operand to LDI R2, 5
+            cpu.regs.pc = 0x200;
+            cpu.set_r(1, 5);
+            exec(&mut cpu, [0x30, 0x01, 0x02, 0x01]);
+            cpu.regs.pc = 0x100;
+            cpu.step();
+            assert_eq!(cpu.r(2), 5);
+
+            // Change the opcode to MOV R2, R0 with a write that starts before the instruction
+            cpu.regs.pc = 0x200;
+            cpu.set_r(3, 0x2400);
+            exec(&mut cpu, [0x30, 0x03, 0xFF, 0x00]);
+            cpu.regs.pc = 0x100;
+            cpu.step();
+            assert_eq!(cpu.r(2), 0);
+        }
+    }
+
+    #[test]
+    fn nop_changes_nothing() {
+        let mut cpu = new_cpu();
+        exec(&mut cpu, [0x00, 0x00, 0x00, 0x00]);
+        assert_eq!((cpu.pc(), cpu.sp(), flags(&cpu)), (4, 0xFDF0, "----"));
+        assert!((0..16).all(|i| cpu.r(i) == 0));
+    }
+
+    #[test]
+    fn instructions_round_trip() {
+        let mut rng = SmallRng::seed_from_u64(0);
+        for op in 0..=255u8 {
+            for _ in 0..16 {
+                let bytes = [op, rng.gen(), rng.gen(), rng.gen()];
+                if let Ok(instr) = Instruction::decode(&bytes) {
+                    assert_eq!(instr.opcode(), op);
+                    assert_eq!(Instruction::decode(&instr.encode()), Ok(instr));
+                    assert_eq!(instr.encode()[0], op);
+                }
+            }
+        }
+        assert_eq!(Instruction::Add3 { rx: 1, ry: 2, rz: 3 }.encode(), [0x42, 0x21, 0x03, 0x00]);
+        assert_eq!(Instruction::Ldi { rx: 1, hhll: 0x1234 }.encode(), [0x20, 0x01, 0x34, 0x12]);
+        assert!(Instruction::decode(&[0x20, 0x01]).is_err());
+    }
+
+    #[test]
+    fn instructions_are_displayed() {
+        let display = |bytes: [u8; 4]| Instruction::decode(&bytes).unwrap().to_string();
+        assert_eq!(display([0x00, 0x00, 0x00, 0x00]), "NOP");
+        assert_eq!(display([0x12, 0x01, 0x34, 0x12]), "JNZ 4660");
+        assert_eq!(display([0x42, 0x21, 0x03, 0x00]), "ADD R1 R2 R3");
+        assert_eq!(display([0x04, 0x00, 0x08, 0x10]), "SPR 8 16");
+    }
+
+    #[test]
+    fn instruction_metadata() {
+        use Instruction::*;
+        let add3 = Add3 { rx: 1, ry: 2, rz: 3 };
+        assert_eq!((add3.rx(), add3.ry(), add3.rz(), add3.hhll()), (Some(1), Some(2), Some(3), None));
+        let sng = Sng { ad: 0x12, ll: 0xA3, hh: 0xC0 };
+        assert_eq!((sng.ad(), sng.ll(), sng.hh(), sng.rx()), (Some(0x12), Some(0xA3), Some(0xC0), None));
+        assert_eq!((Cx { cond: 1, hhll: 0x200 }.cond(), Cx { cond: 1, hhll: 0x200 }.hhll()), (Some(1), Some(0x200)));
+        assert_eq!((Nop {}.cond(), Nop {}.hhll()), (None, None));
+        assert_eq!((add3.reads(), add3.writes()), (vec![1, 2], vec![3]));
+        assert_eq!((Addi { rx: 4, hhll: 1 }.reads(), Addi { rx: 4, hhll: 1 }.writes()), (vec![4], vec![4]));
+        assert_eq!((Mov { rx: 1, ry: 2 }.reads(), Mov { rx: 1, ry: 2 }.writes()), (vec![2], vec![1]));
+        assert_eq!((Cmp { rx: 1, ry: 2 }.reads(), Cmp { rx: 1, ry: 2 }.writes()), (vec![1, 2], vec![]));
+        assert_eq!((Pushall {}.reads().len(), Popall {}.writes().len()), (16, 16));
+
+        assert!(Jx { cond: 1, hhll: 0 }.is_branch() && Ret {}.is_branch() && !Add { rx: 0, ry: 0 }.is_branch());
+        assert!(Stm { rx: 0, hhll: 0 }.touches_memory() && Call { hhll: 0 }.touches_memory());
+        assert!(!Mov { rx: 0, ry: 0 }.touches_memory());
+    }
+
+    #[test]
+    fn unknown_opcodes_are_rejected() {
+        let mut cpu = new_cpu();
+        for op in 0..=255u8 {
+            cpu.memory[0x100] = op;
+            if Instruction::decode(&cpu.memory[0x100..]).is_err() {
+                assert_eq!(run_instruction(&mut cpu, 0x100), Err(format!("Unknown Opcode 0x{:02X}", op)));
+            }
+        }
+    }
+
+    #[test]
+    fn unknown_opcodes_raise_a_trap() {
+        for &decode_cache in &[true, false] {
+            let mut cpu = new_cpu();
+            cpu.set_decode_cache(decode_cache);
+            cpu.set_r(1, 7);
+            exec(&mut cpu, [0xFF, 0x01, 0x00, 0x00]);
+
+            // The instruction is skipped instead of aborting the emulator
+            assert_eq!((cpu.r(1), cpu.pc()), (7, 4));
+            let reason = "invalid instruction (Unknown Opcode 0xFF)".to_string();
+            assert_eq!(cpu.take_trap(), Some(Trap { pc: 0, reason }));
+        }
+    }
+}
@@ -0,0 +1,488 @@
+use rand::Rng;
+use rand::SeedableRng;
+use rand::rngs::SmallRng;
+
+use super::Cpu;
+
+const C: u8 = 0x02;
+const Z: u8 = 0x04;
+const O: u8 = 0x40;
+const N: u8 = 0x80;
+
+/// A straightforward interpreter written from the specification, used to check `Cpu` against.
+/// Behaviour the specification leaves undefined (e.g. division by zero or accesses past the end of the memory)
+/// is reported as unsupported instead of being guessed.
+#[derive(Clone)]
+pub struct Reference {
+    pub pc: u16,
+    pub sp: u16,
+    pub r: [u16; 16],
+    pub flags: u8,
+    pub mem: Vec<u8>,
+    pub fg: Vec<u8>,
+    pub bg: u8,
+    pub spritew: u8,
+    pub spriteh: u8,
+    pub hflip: bool,
+    pub vflip: bool,
+    pub palette: Option<[u32; 16]>,
+}
+
+impl Reference {
+    /// Copy the state of a cpu that has not drawn anything yet
+    pub fn new(cpu: &Cpu) -> Reference {
+        Reference {
+            pc: cpu.regs.pc,
+            sp: cpu.regs.sp,
+            r: cpu.regs.r.map(|r| r as u16),
+            flags: cpu.regs.flags,
+            mem: cpu.memory.clone(),
+            fg: vec![0; 320 * 240],
+            bg: 0,
+            spritew: 0,
+            spriteh: 0,
+            hflip: false,
+            vflip: false,
+            palette: None,
+        }
+    }
+
+    /// The frame as palette indices
+    pub fn frame(&self) -> Vec<u8> {
+        self.fg.iter().map(|&p| if p == 0 { self.bg } else { p }).collect()
+    }
+
+    fn read16(&self, addr: u16) -> Result<u16, String> {
+        if addr == 0xFFFF {
+            return Err("read past the end of the memory".into());
+        }
+        Ok(self.mem[addr as usize] as u16 | (self.mem[addr as usize + 1] as u16) << 8)
+    }
+
+    fn write16(&mut self, addr: u16, val: u16) -> Result<(), String> {
+        if addr == 0xFFFF {
+            return Err("write past the end of the memory".into());
+        }
+        self.mem[addr as usize] = val as u8;
+        self.mem[addr as usize + 1] = (val >> 8) as u8;
+        Ok(())
+    }
+
+    fn push(&mut self, val: u16) -> Result<(), String> {
+        if self.sp > 0xFFFD {
+            return Err("stack overflow".into());
+        }
+        self.write16(self.sp, val)?;
+        self.sp += 2;
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Result<u16, String> {
+        if self.sp < 2 {
+            return Err("stack underflow".into());
+        }
+        self.sp -= 2;
+        self.read16(self.sp)
+    }
+
+    fn flag(&self, flag: u8) -> bool {
+        self.flags & flag != 0
+    }
+
+    fn set(&mut self, flag: u8, val: bool) {
+        if val { self.flags |= flag } else { self.flags &= !flag }
+    }
+
+    /// Set the zero and negative flags of a result
+    fn zn(&mut self, res: u16) -> u16 {
+        self.set(Z, res == 0);
+        self.set(N, res & 0x8000 != 0);
+        res
+    }
+
+    fn cond(&self, cond: u8) -> Result<bool, String> {
+        let (c, z, o, n) = (self.flag(C), self.flag(Z), self.flag(O), self.flag(N));
+        Ok(match cond {
+            0x0 => z,
+            0x1 => !z,
+            0x2 => n,
+            0x3 => !n,
+            0x4 => !n && !z,
+            0x5 => o,
+            0x6 => !o,
+            0x7 => !c && !z,
+            0x8 => !c,
+            0x9 => c,
+            0xA => c || z,
+            0xB => o == n && !z,
+            0xC => o == n,
+            0xD => o != n,
+            0xE => o != n || z,
+            _ => return Err(format!("unknown condition {}", cond)),
+        })
+    }
+
+    /// The arithmetic and logic operations, `None` for unknown kinds.
+    /// Like every arithmetic and logic instruction they clear all flags before setting the affected ones
+    fn alu(&mut self, op: u8, a: u16, b: u16) -> Option<u16> {
+        let sign = |v: u16| v & 0x8000 != 0;
+        self.flags = 0;
+        Some(match op {
+            0x4 => {
+                let res = a as u32 + b as u32;
+                self.set(C, res > 0xFFFF);
+                self.set(O, sign(a) == sign(b) && sign(res as u16) != sign(a));
+                self.zn(res as u16)
+            },
+            0x5 => {
+                let res = a.wrapping_sub(b);
+                self.set(C, a < b);
+                self.set(O, sign(a) != sign(b) && sign(res) != sign(a));
+                self.zn(res)
+            },
+            0x6 => self.zn(a & b),
+            0x7 => self.zn(a | b),
+            0x8 => self.zn(a ^ b),
+            0x9 => {
+                let res = a as u32 * b as u32;
+                let signed = a as i16 as i32 * b as i16 as i32;
+                self.set(C, res > 0xFFFF);
+                self.set(O, signed != signed as i16 as i32);
+                self.zn(res as u16)
+            },
+            _ => return None,
+        })
+    }
+
+    fn div(&mut self, kind: u8, a: u16, b: u16) -> Result<u16, String> {
+        let (sa, sb) = (a as i16 as i32, b as i16 as i32);
+        if sb == 0 {
+            return Err("division by zero".into());
+        }
+        self.flags = 0;
+        let res = match kind {
+            0 => {
+                self.set(C, sa % sb != 0);
+                self.set(O, sa / sb > 0x7FFF);
+                sa / sb
+            },
+            1 => ((sa % sb) + sb) % sb,
+            _ => sa % sb,
+        };
+        Ok(self.zn(res as u16))
+    }
+
+    fn shift(&mut self, kind: u8, a: u16, n: u16) -> u16 {
+        self.flags = 0;
+        let res = match kind {
+            0 => if n >= 16 { 0 } else { ((a as u32) << n) as u16 },
+            1 => if n >= 16 { 0 } else { a >> n },
+            _ => ((a as i16 as i32) >> n.min(15)) as u16,
+        };
+        self.zn(res)
+    }
+
+    fn draw(&mut self, x: u16, y: u16, addr: u16) -> Result<(), String> {
+        let (w, h) = (self.spritew as i32, self.spriteh as i32);
+        if addr as i32 + w * h > 0x10000 {
+            return Err("sprite past the end of the memory".into());
+        }
+        let (x, y) = (x as i16 as i32, y as i16 as i32);
+        let mut overlap = false;
+        for row in 0..h {
+            for col in 0..w * 2 {
+                let src_row = if self.vflip { h - 1 - row } else { row };
+                let src_col = if self.hflip { w * 2 - 1 - col } else { col };
+                let byte = self.mem[(addr as i32 + src_row * w + src_col / 2) as usize];
+                let pixel = if src_col % 2 == 0 { byte >> 4 } else { byte & 0xF };
+                let (px, py) = (x + col, y + row);
+                if pixel == 0 || !(0..320).contains(&px) || !(0..240).contains(&py) {
+                    continue;
+                }
+                let p = (px + py * 320) as usize;
+                overlap |= self.fg[p] != 0;
+                self.fg[p] = pixel;
+            }
+        }
+        self.set(C, overlap);
+        Ok(())
+    }
+
+    /// Execute one instruction, the state is unchanged if it is unsupported
+    pub fn step(&mut self) -> Result<(), String> {
+        let backup = self.clone();
+        let res = self.execute();
+        if res.is_err() {
+            *self = backup;
+        }
+        res
+    }
+
+    fn execute(&mut self) -> Result<(), String> {
+        if self.pc > 0xFFFB {
+            return Err("instruction past the end of the memory".into());
+        }
+        let pc = self.pc as usize;
+        let (op, b1, b2, b3) = (self.mem[pc], self.mem[pc + 1], self.mem[pc + 2], self.mem[pc + 3]);
+        let (x, y, z) = ((b1 & 0xF) as usize, (b1 >> 4) as usize, (b2 & 0xF) as usize);
+        let imm = b2 as u16 | (b3 as u16) << 8;
+        self.pc += 4;
+
+        match op {
+            0x00 => {},
+            0x01 => {
+                self.fg.iter_mut().for_each(|p| *p = 0);
+                self.bg = 0;
+            },
+            // Nothing is rendered, so VBLNK always waits
+            0x02 => self.pc -= 4,
+            0x03 => {
+                if b2 > 0xF {
+                    return Err(format!("background color {}", b2));
+                }
+                self.bg = b2;
+            },
+            0x04 => {
+                self.spritew = b2;
+                self.spriteh = b3;
+            },
+            0x05 => self.draw(self.r[x], self.r[y], imm)?,
+            0x06 => self.draw(self.r[x], self.r[y], self.r[z])?,
+            0x08 => {
+                self.hflip = b3 & 2 != 0;
+                self.vflip = b3 & 1 != 0;
+            },
+            0x09..=0x0C | 0x0E => {},
+            0x0D => {
+                if self.read16(self.r[x])? == 0 {
+                    return Err("tone of 0 Hz".into());
+                }
+            },
+            0x10 => self.pc = imm,
+            0x11 => if self.flag(C) { self.pc = imm },
+            0x12 => if self.cond(b1)? { self.pc = imm },
+            0x13 => if self.r[x] == self.r[y] { self.pc = imm },
+            0x14 => {
+                self.push(self.pc)?;
+                self.pc = imm;
+            },
+            0x15 => self.pc = self.pop()?,
+            0x16 => self.pc = self.r[x],
+            0x17 => if self.cond(b1)? {
+                self.push(self.pc)?;
+                self.pc = imm;
+            },
+            0x18 => {
+                self.push(self.pc)?;
+                self.pc = self.r[x];
+            },
+            0x20 => self.r[x] = imm,
+            0x21 => self.sp = imm,
+            0x22 => self.r[x] = self.read16(imm)?,
+            0x23 => self.r[x] = self.read16(self.r[y])?,
+            0x24 => self.r[x] = self.r[y],
+            0x30 => self.write16(imm, self.r[x])?,
+            0x31 => self.write16(self.r[y], self.r[x])?,
+            0x40..=0x92 if op & 0xF <= 4 => {
+                let kind = op >> 4;
+                match (op & 0xF, kind) {
+                    (0, _) => self.r[x] = self.alu(kind, self.r[x], imm).unwrap(),
+                    (1, _) => self.r[x] = self.alu(kind, self.r[x], self.r[y]).unwrap(),
+                    (2, _) => self.r[z] = self.alu(kind, self.r[x], self.r[y]).unwrap(),
+                    // CMPI, CMP, TSTI and TST discard the result
+                    (3, 0x5) | (3, 0x6) => { self.alu(kind, self.r[x], imm); },
+                    (4, 0x5) | (4, 0x6) => { self.alu(kind, self.r[x], self.r[y]); },
+                    _ => return Err(format!("unknown opcode 0x{:02X}", op)),
+                }
+            },
+            0xA0..=0xA8 => {
+                let (kind, form) = ((op & 0xF) / 3, (op & 0xF) % 3);
+                match form {
+                    0 => self.r[x] = self.div(kind, self.r[x], imm)?,
+                    1 => self.r[x] = self.div(kind, self.r[x], self.r[y])?,
+                    _ => self.r[z] = self.div(kind, self.r[x], self.r[y])?,
+                }
+            },
+            0xB0..=0xB2 => self.r[x] = self.shift(op & 0xF, self.r[x], imm),
+            0xB3..=0xB5 => self.r[x] = self.shift((op & 0xF) - 3, self.r[x], self.r[y]),
+            0xC0 => self.push(self.r[x])?,
+            0xC1 => self.r[x] = self.pop()?,
+            0xC2 => {
+                for i in 0..16 {
+                    self.push(self.r[i])?;
+                }
+            },
+            0xC3 => {
+                for i in (0..16).rev() {
+                    self.r[i] = self.pop()?;
+                }
+            },
+            0xC4 => self.push(self.flags as u16)?,
+            0xC5 => self.flags = self.pop()? as u8,
+            0xD0 | 0xD1 => {
+                let addr = if op == 0xD0 { imm } else { self.r[x] } as usize;
+                if addr + 48 > 0x10000 {
+                    return Err("palette past the end of the memory".into());
+                }
+                let mut palette = [0; 16];
+                for (i, color) in palette.iter_mut().enumerate() {
+                    let rgb = &self.mem[addr + i * 3..addr + i * 3 + 3];
+                    *color = (rgb[0] as u32) << 16 | (rgb[1] as u32) << 8 | rgb[2] as u32;
+                }
+                self.palette = Some(palette);
+            },
+            0xE0..=0xE5 => {
+                let val = match op {
+                    0xE0 | 0xE3 => imm,
+                    0xE1 | 0xE4 => self.r[x],
+                    _ => self.r[y],
+                };
+                self.flags = 0;
+                self.r[x] = self.zn(if op < 0xE3 { !val } else { 0u16.wrapping_sub(val) });
+            },
+            _ => return Err(format!("unknown opcode 0x{:02X}", op)),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::panic::{self, AssertUnwindSafe};
+
+    use crate::apu::Apu;
+    use crate::cpu::reference::*;
+    use crate::gpu::Gpu;
+    use crate::rom::RomBuilder;
+
+    /// The opcodes that are generated, `RND` is left out as the generators differ
+    static OPCODES: [u8; 81] = [
+        0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x08, 0x09, 0x0A, 0x0B, 0x0C, 0x0D, 0x0E,
+        0x10, 0x11, 0x12, 0x13, 0x14, 0x15, 0x16, 0x17, 0x18,
+        0x20, 0x21, 0x22, 0x23, 0x24, 0x30, 0x31,
+        0x40, 0x41, 0x42, 0x50, 0x51, 0x52, 0x53, 0x54, 0x60, 0x61, 0x62, 0x63, 0x64,
+        0x70, 0x71, 0x72, 0x80, 0x81, 0x82, 0x90, 0x91, 0x92,
+        0xA0, 0xA1, 0xA2, 0xA3, 0xA4, 0xA5, 0xA6, 0xA7, 0xA8,
+        0xB0, 0xB1, 0xB2, 0xB3, 0xB4, 0xB5,
+        0xC0, 0xC1, 0xC2, 0xC3, 0xC4, 0xC5, 0xD0, 0xD1,
+        0xE0, 0xE1, 0xE2, 0xE3, 0xE4, 0xE5,
+    ];
+
+    /// The start of the memory that is filled with random data
+    const DATA: u16 = 0x8000;
+    const DATA_SIZE: u16 = 0x800;
+    const MAX_STEPS: usize = 64;
+
+    /// A value that is likely to hit corner cases
+    fn value(rng: &mut SmallRng) -> u16 {
+        match rng.gen_range(0, 6) {
+            0 => [0, 1, 2, 0xFFFF, 0x7FFF, 0x8000, 0x8001, 15, 16, 17][rng.gen_range(0, 10)],
+            1 => rng.gen_range(0, 16),
+            2 => DATA + rng.gen_range(0, DATA_SIZE),
+            _ => rng.gen(),
+        }
+    }
+
+    fn instruction(rng: &mut SmallRng, len: u16) -> [u8; 4] {
+        let op = OPCODES[rng.gen_range(0, OPCODES.len())];
+        let [ll, hh] = value(rng).to_le_bytes();
+        let mut bytes = [op, rng.gen(), ll, hh];
+        match op {
+            // Keep jumps inside the program
+            0x10 | 0x11 | 0x12 | 0x13 | 0x14 | 0x17 => {
+                bytes[2..].copy_from_slice(&(rng.gen_range(0, len + 2) * 4).to_le_bytes());
+                if op == 0x12 || op == 0x17 {
+                    bytes[1] = rng.gen_range(0, 15);
+                }
+            },
+            0x03 => bytes[2] = rng.gen_range(0, 16),
+            0x04 => {
+                bytes[2] = rng.gen_range(0, 9);
+                bytes[3] = rng.gen_range(0, 9);
+            },
+            0x08 => bytes[3] = rng.gen_range(0, 4),
+            0x21 => bytes[2..].copy_from_slice(&[0xFDF0, 0xFFFC, 0x0002, value(rng)][rng.gen_range(0, 4)].to_le_bytes()),
+            _ => {},
+        }
+        bytes
+    }
+
+    /// Compare the registers and flags, the memory and frame are only compared with `all`
+    fn diff(cpu: &Cpu, reference: &Reference, all: bool) -> Vec<String> {
+        let mut diff = Vec::new();
+        let mut check = |name: &str, a: String, b: String| if a != b {
+            diff.push(format!("{}: cpu {}, reference {}", name, a, b));
+        };
+        check("pc", format!("0x{:04X}", cpu.regs.pc), format!("0x{:04X}", reference.pc));
+        check("sp", format!("0x{:04X}", cpu.regs.sp), format!("0x{:04X}", reference.sp));
+        check("flags", format!("0b{:08b}", cpu.regs.flags), format!("0b{:08b}", reference.flags));
+        for i in 0..16 {
+            check(&format!("R{:X}", i), cpu.regs.r[i].to_string(), (reference.r[i] as i16).to_string());
+        }
+        if all {
+            if let Some(addr) = (0..cpu.memory.len()).find(|&i| cpu.memory[i] != reference.mem[i]) {
+                check(&format!("memory at 0x{:04X}", addr), cpu.memory[addr].to_string(), reference.mem[addr].to_string());
+            }
+            let (frame, expected) = (cpu.gpu.frame(), reference.frame());
+            if let Some(p) = (0..frame.len()).find(|&p| frame[p] != expected[p]) {
+                check(&format!("pixel ({}, {})", p % 320, p / 320), frame[p].to_string(), expected[p].to_string());
+            }
+            if let Some(palette) = reference.palette {
+                check("palette", format!("{:06X?}", cpu.gpu.rom_palette()), format!("{:06X?}", palette));
+            }
+        }
+        diff
+    }
+
+    /// Run a random program on both the cpu and the reference and return the divergence if any
+    fn run(seed: u64) -> Result<(), String> {
+        let mut rng = SmallRng::seed_from_u64(seed);
+        let len = rng.gen_range(1, 32);
+        let program: Vec<u8> = (0..len).flat_map(|_| instruction(&mut rng, len).to_vec()).collect();
+
+        let mut cpu = Cpu::new(Gpu::new(), Apu::silent(0.0), &RomBuilder::new(program).build());
+        for i in 0..16 {
+            cpu.regs.r[i] = value(&mut rng) as i16;
+        }
+        cpu.regs.flags = rng.gen::<u8>() & (C | Z | O | N);
+        for i in DATA..DATA + DATA_SIZE {
+            cpu.memory[i as usize] = rng.gen();
+        }
+        let mut reference = Reference::new(&cpu);
+
+        let mut trace = Vec::new();
+        for _ in 0..MAX_STEPS {
+            if reference.step().is_err() {
+                break;
+            }
+            trace.push(format!("0x{:04X}  {}", cpu.regs.pc, cpu.format_instruction(cpu.regs.pc).unwrap_or_default()));
+            if let Err(e) = panic::catch_unwind(AssertUnwindSafe(|| cpu.step())) {
+                let e = e.downcast_ref::<String>().cloned()
+                    .or_else(|| e.downcast_ref::<&str>().map(|e| e.to_string()))
+                    .unwrap_or_default();
+                return Err(format!("seed {}: the cpu panicked ({}) after\n{}", seed, e, trace.join("\n")));
+            }
+            let diff = diff(&cpu, &reference, false);
+            if !diff.is_empty() {
+                return Err(format!("seed {}: {} after\n{}", seed, diff.join(", "), trace.join("\n")));
+            }
+        }
+
+        let diff = diff(&cpu, &reference, true);
+        if !diff.is_empty() {
+            return Err(format!("seed {}: {} after\n{}", seed, diff.join(", "), trace.join("\n")));
+        }
+        Ok(())
+    }
+
+    /// Run random programs, set `RCHIP16_FUZZ_ITERATIONS` and `RCHIP16_FUZZ_SEED` for longer or other runs
+    #[test]
+    fn cpu_matches_reference() {
+        let var = |name: &str, default: u64| env::var(name).ok().and_then(|v| v.parse().ok()).unwrap_or(default);
+        let (iterations, seed) = (var("RCHIP16_FUZZ_ITERATIONS", 1000), var("RCHIP16_FUZZ_SEED", 0));
+        if let Some(failure) = (seed..seed + iterations).map(run).find_map(Result::err) {
+            panic!("{}", failure);
+        }
+    }
+}
@@ -3,17 +3,20 @@ use std::io::Write;
 use std::collections::HashSet;
 use std::process;
 
+use crate::capture;
 use crate::cpu::*;
+use crate::viewer::format_draw;
 
 pub struct Debugger {
     run: bool,
+    trace: bool,
     break_pc: HashSet<u16>,
     break_op: HashSet<String>,
 }
 
 impl Debugger {
     pub fn new() -> Debugger {
-        Debugger { run: false, break_pc: HashSet::new(), break_op: HashSet::new() }
+        Debugger { run: false, trace: false, break_pc: HashSet::new(), break_op: HashSet::new() }
     }
 
     /// Set the break flag
@@ -21,13 +24,19 @@ impl Debugger {
         self.run = true;
     }
 
+    /// Set the trace flag, which prints every instruction before it is executed
+    pub fn set_trace(&mut self, val: bool) {
+        self.trace = val;
+    }
+
     /// Perform one step and run the debugger if requested
     pub fn step(&mut self, cpu: &mut Cpu) {
+        if self.trace {
+            println!("0x{:04X}  {}", cpu.pc(), format_instruction(cpu, cpu.pc()));
+        }
 
-        if !self.break_pc.is_empty() {
-            if self.break_pc.contains(&cpu.pc()) {
-                self.run = true;
-            }
+        if self.break_pc.contains(&cpu.pc()) {
+            self.run = true;
         }
 
         if !self.break_op.is_empty() {
@@ -78,6 +87,8 @@ impl Debugger {
                     },
                     None => println!("invalid argument"),
                 },
+                Some("sp") => print_draws(cpu),
+                Some("pal") => print_palette(cpu),
                 Some("q") => process::exit(0),
                 Some(_) => println!("unknown command"),
             }
@@ -96,11 +107,67 @@ fn format_instruction(cpu: &Cpu, addr: u16) -> String {
 fn print_current_instructions(cpu: &Cpu) {
     let pc = cpu.pc();
     if pc > 0 {
-        println!("{:>19} {}", "|", format_instruction(cpu, pc - 4));
+        println!("{:>19} {}", "|", format_instruction(cpu, pc.wrapping_sub(4)));
     }
     println!("  PC 0x{:04X} ----> | {}", pc, format_instruction(cpu, pc));
+    if let Ok(instr) = cpu.instruction(pc) {
+        print_operands(cpu, instr);
+    }
     for i in 1..4 {
-        println!("{:>19} {}", "|", format_instruction(cpu, pc + i * 4));
+        println!("{:>19} {}", "|", format_instruction(cpu, pc.wrapping_add(i * 4)));
+    }
+}
+
+/// Print the registers an instruction reads with their values, the ones it writes and what else it does
+fn print_operands(cpu: &Cpu, instr: Instruction) {
+    let list = |regs: Vec<String>| if regs.is_empty() { "-".to_string() } else { regs.join(" ") };
+    let reads = list(instr.reads().iter().map(|&r| format!("R{:X}={}", r, cpu.r(r))).collect());
+    let writes = list(instr.writes().iter().map(|&r| format!("R{:X}", r)).collect());
+    let mut line = format!("{:>19}   reads {}, writes {}", "|", reads, writes);
+    if instr.touches_memory() {
+        line.push_str(", memory");
+    }
+    if instr.is_branch() {
+        line.push_str(", branch");
+    }
+    println!("{}", line);
+}
+
+/// Print the sprites of the last rendered frame and the ones drawn since,
+/// the sprites are only logged once this has been called or the sprite viewer is open
+fn print_draws(cpu: &mut Cpu) {
+    if !cpu.gpu().draw_log() {
+        cpu.gpu_mut().set_draw_log(true);
+        println!("sprite log enabled");
+        return;
+    }
+    let gpu = cpu.gpu();
+    println!("last frame:");
+    for (i, draw) in gpu.frame_draws().iter().enumerate() {
+        println!("  {}", format_draw(i, draw));
+    }
+    println!("current frame:");
+    for (i, draw) in gpu.draws().iter().enumerate() {
+        println!("  {}", format_draw(i, draw));
+    }
+}
+
+/// Print the 16 colors of the palette with a swatch each, where it was loaded from and the forced palette
+fn print_palette(cpu: &Cpu) {
+    let gpu = cpu.gpu();
+    match gpu.palette_source() {
+        Some((addr, frame)) => println!("palette loaded from 0x{:04X} in frame {}", addr, frame),
+        None => println!("default palette (frame {})", gpu.frames()),
+    }
+    let swatch = |color: u32| {
+        let [r, g, b] = capture::rgb(color);
+        format!("\x1b[48;2;{};{};{}m    \x1b[0m 0x{:06X}", r, g, b, color)
+    };
+    for (i, &color) in gpu.rom_palette().iter().enumerate() {
+        match gpu.forced_palette() {
+            Some(forced) => println!("  {:>2} {}  forced {}", i, swatch(color), swatch(forced[i])),
+            None => println!("  {:>2} {}", i, swatch(color)),
+        }
     }
 }
 
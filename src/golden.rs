@@ -0,0 +1,127 @@
+use std::env;
+use std::fs::{self, File};
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+
+use png::{BitDepth, ColorType, Decoder, Encoder, Transformations};
+
+use crate::capture::{self, HEIGHT, WIDTH};
+use crate::gpu::Gpu;
+
+/// The number of differing pixels listed in a report
+const REPORTED_PIXELS: usize = 10;
+
+/// Load a PNG of the size 320x240 as colors of the form `0xRRGGBB`, indexed images are expanded
+pub fn load_png<P: AsRef<Path>>(path: P) -> Result<Vec<u32>, String> {
+    let path = path.as_ref();
+    let file = File::open(path).map_err(|e| format!("Error opening {}: {}", path.display(), e))?;
+    let mut decoder = Decoder::new(file);
+    decoder.set_transformations(Transformations::EXPAND | Transformations::STRIP_16);
+    let (info, mut reader) = decoder.read_info().map_err(|e| format!("Error decoding {}: {}", path.display(), e))?;
+    if (info.width as usize, info.height as usize) != (WIDTH, HEIGHT) {
+        return Err(format!("{} is {}x{} instead of {}x{}", path.display(), info.width, info.height, WIDTH, HEIGHT));
+    }
+
+    let mut data = vec![0; info.buffer_size()];
+    reader.next_frame(&mut data).map_err(|e| format!("Error decoding {}: {}", path.display(), e))?;
+    let channels = match info.color_type {
+        ColorType::RGB => 3,
+        ColorType::RGBA => 4,
+        color => return Err(format!("{} has the unsupported color type {:?}", path.display(), color)),
+    };
+    Ok(data.chunks(channels).map(|p| (p[0] as u32) << 16 | (p[1] as u32) << 8 | p[2] as u32).collect())
+}
+
+/// The colors of the current frame
+pub fn frame_colors(gpu: &Gpu) -> Vec<u32> {
+    let palette = gpu.palette();
+    gpu.frame().iter().map(|&p| palette[p as usize]).collect()
+}
+
+/// Compare the current frame against the reference PNG at `path`.
+/// If they differ, a diff image is written next to it with the extension `diff.png`
+/// and the differing pixels are reported in the error.
+/// With the environment variable `RCHIP16_BLESS` set, the reference is written instead.
+pub fn compare<P: AsRef<Path>>(gpu: &Gpu, path: P) -> Result<(), String> {
+    let path = path.as_ref();
+    if env::var_os("RCHIP16_BLESS").is_some() {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+        }
+        return gpu.save_png(path, false).map_err(|e| format!("Error writing {}: {}", path.display(), e));
+    }
+
+    let expected = load_png(path)?;
+    let actual = frame_colors(gpu);
+    let diff: Vec<usize> = (0..actual.len()).filter(|&i| actual[i] != expected[i]).collect();
+    if diff.is_empty() {
+        return Ok(());
+    }
+
+    let diff_path = diff_path(path);
+    write_diff(&diff_path, &actual, &diff).map_err(|e| format!("Error writing {}: {}", diff_path.display(), e))?;
+
+    let mut report = format!("{} of {} pixels differ from {}, see {}",
+                             diff.len(), actual.len(), path.display(), diff_path.display());
+    for &i in diff.iter().take(REPORTED_PIXELS) {
+        report += &format!("\n  ({:>3}, {:>3}): expected 0x{:06X}, got 0x{:06X}", i % WIDTH, i / WIDTH, expected[i], actual[i]);
+    }
+    if diff.len() > REPORTED_PIXELS {
+        report += &format!("\n  ... and {} more", diff.len() - REPORTED_PIXELS);
+    }
+    Err(report)
+}
+
+/// Panic with a report if the current frame differs from the reference PNG at `path`
+pub fn assert_frame<P: AsRef<Path>>(gpu: &Gpu, path: P) {
+    if let Err(report) = compare(gpu, path) {
+        panic!("{}", report);
+    }
+}
+
+fn diff_path(path: &Path) -> PathBuf {
+    path.with_extension("diff.png")
+}
+
+/// Write the frame darkened with the differing pixels in red
+fn write_diff(path: &Path, actual: &[u32], diff: &[usize]) -> std::io::Result<()> {
+    let mut colors: Vec<u32> = actual.iter().map(|&c| c >> 2 & 0x3F3F3F).collect();
+    for &i in diff {
+        colors[i] = 0xFF0000;
+    }
+
+    let mut encoder = Encoder::new(BufWriter::new(File::create(path)?), WIDTH as u32, HEIGHT as u32);
+    encoder.set_color(ColorType::RGB);
+    encoder.set_depth(BitDepth::Eight);
+    let data: Vec<u8> = colors.iter().flat_map(|&c| capture::rgb(c).to_vec()).collect();
+    encoder.write_header()?.write_image_data(&data)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::golden::*;
+
+    #[test]
+    fn compare_reports_differences() {
+        let dir = env::temp_dir().join(format!("rchip16-golden-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let reference = dir.join("frame.png");
+
+        let mut gpu = Gpu::new();
+        gpu.set_sprite_size(1, 1);
+        gpu.draw(10, 20, &[0x33]);
+        gpu.save_png(&reference, true).unwrap();
+        assert_frame(&gpu, &reference);
+
+        gpu.draw(100, 200, &[0x40]);
+        let report = compare(&gpu, &reference).unwrap_err();
+        assert!(report.starts_with("1 of 76800 pixels differ"), "{}", report);
+        assert!(report.contains("(100, 200): expected 0x000000, got 0xDE7AAE"), "{}", report);
+
+        let diff = load_png(diff_path(&reference)).unwrap();
+        assert_eq!(diff[100 + 200 * WIDTH], 0xFF0000);
+        assert_eq!(diff[10 + 20 * WIDTH], 0xBF3932 >> 2 & 0x3F3F3F);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
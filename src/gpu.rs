@@ -1,6 +1,11 @@
 use std::cmp::{max, min};
-use std::mem::swap;
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::mem;
+use std::path::Path;
+use std::time::{Duration, Instant};
 
+use crate::capture;
 use crate::util::*;
 
 static DEFAULT_PALETTE: [u32; 16] = [
@@ -22,6 +27,72 @@ static DEFAULT_PALETTE: [u32; 16] = [
     0xFFFFFF, // White
 ];
 
+/// The default palette with every color pushed to the most distinct one of its hue
+static HIGH_CONTRAST_PALETTE: [u32; 16] = [
+    0x000000, 0x000000, 0x808080, 0xFF0000, 0xFF00FF, 0x603000, 0xA05000, 0xFF8000,
+    0xFFFF00, 0x008000, 0x00FF00, 0x202060, 0x0000FF, 0x00C0FF, 0xC0FFFF, 0xFFFFFF,
+];
+
+/// The default palette mapped to the Okabe-Ito colors, which stay distinct with red-green color blindness
+static COLORBLIND_PALETTE: [u32; 16] = [
+    0x000000, 0x000000, 0x888888, 0xD55E00, 0xCC79A7, 0x4C3D21, 0x8C6D2C, 0xE69F00,
+    0xF0E442, 0x009E73, 0x7FD8BE, 0x252E38, 0x0072B2, 0x56B4E9, 0xBCDEE4, 0xFFFFFF,
+];
+
+/// The names of the palettes that can be forced
+pub static PALETTES: [&str; 3] = ["default", "high-contrast", "colorblind"];
+
+/// Find a palette by its name
+pub fn named_palette(name: &str) -> Option<[u32; 16]> {
+    match name.to_lowercase().as_str() {
+        "default" => Some(DEFAULT_PALETTE),
+        "high-contrast" => Some(HIGH_CONTRAST_PALETTE),
+        "colorblind" => Some(COLORBLIND_PALETTE),
+        _ => None,
+    }
+}
+
+/// A way of rendering the frame that helps debugging
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum View {
+    /// Render the frame as is
+    Normal,
+    /// Tint the pixels in red where sprites overlapped since the last frame
+    Overlap,
+    /// Show the background as checkerboard to tell it apart from the foreground
+    Layers,
+    /// Flash the pixels written by the most recent `DRW`
+    LastDraw,
+}
+
+impl View {
+    /// The next view, cycling back to the normal one
+    pub fn next(self) -> View {
+        match self {
+            View::Normal => View::Overlap,
+            View::Overlap => View::Layers,
+            View::Layers => View::LastDraw,
+            View::LastDraw => View::Normal,
+        }
+    }
+}
+
+/// A sprite drawn by `DRW`
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct DrawCall {
+    /// The address of the sprite data
+    pub addr: u16,
+    pub x: i16,
+    pub y: i16,
+    /// The width in bytes, each byte holds two pixels
+    pub w: u8,
+    pub h: u8,
+    pub hflip: bool,
+    pub vflip: bool,
+    /// Whether the sprite overlapped with a pixel that was already drawn
+    pub overlap: bool,
+}
+
 pub struct Gpu {
     /// Frame foreground buffer
     fg: [u8; 320 * 240],
@@ -37,8 +108,34 @@ pub struct Gpu {
     vflip: bool,
     /// Color palette
     palette: [u32; 16],
+    /// The address the palette was loaded from by `PAL`
+    palette_addr: Option<u16>,
+    /// The frame in which the palette was loaded
+    palette_frame: u64,
+    /// A palette that is used instead of the default and the loaded ones
+    forced_palette: Option<[u32; 16]>,
+    /// The number of rendered frames
+    frames: u64,
     /// Flag to signal that a new frame has been drawn
     vblank: bool,
+    /// The debug view the frame is rendered with
+    view: View,
+    /// The pixels where sprites overlapped since the last frame, only tracked in debug views
+    overlap_mask: Vec<bool>,
+    /// The number of the `DRW` that last wrote each pixel, only tracked in debug views
+    last_draw_mask: Vec<u32>,
+    /// The number of the most recent `DRW`, the pixels marked with it are the ones it wrote
+    last_draw: u32,
+    /// Flag to signal that draw calls should be logged
+    draw_log: bool,
+    /// The sprites drawn in the current frame
+    draws: Vec<DrawCall>,
+    /// The sprites drawn in the last rendered frame
+    frame_draws: Vec<DrawCall>,
+    /// Flag to signal that the time spent drawing sprites should be measured
+    profile: bool,
+    /// The time spent drawing sprites since profiling was enabled
+    draw_time: Duration,
 }
 
 impl Gpu {
@@ -52,6 +149,19 @@ impl Gpu {
             vflip: false,
             vblank: false,
             palette: DEFAULT_PALETTE,
+            palette_addr: None,
+            palette_frame: 0,
+            forced_palette: None,
+            frames: 0,
+            view: View::Normal,
+            overlap_mask: vec![false; 320 * 240],
+            last_draw_mask: vec![0; 320 * 240],
+            last_draw: 1,
+            draw_log: false,
+            draws: Vec::new(),
+            frame_draws: Vec::new(),
+            profile: false,
+            draw_time: Duration::ZERO,
         }
     }
 
@@ -77,6 +187,11 @@ impl Gpu {
         self.spriteh = h;
     }
 
+    /// The width in bytes and the height of sprites
+    pub fn sprite_size(&self) -> (u8, u8) {
+        (self.spritew, self.spriteh)
+    }
+
     pub fn set_hflip(&mut self, val: bool) {
         self.hflip = val;
     }
@@ -85,37 +200,176 @@ impl Gpu {
         self.vflip = val;
     }
 
+    /// The active palette, which is the forced one if set
+    pub fn palette(&self) -> &[u32; 16] {
+        self.forced_palette.as_ref().unwrap_or(&self.palette)
+    }
+
+    /// The palette set by the rom or the configuration, regardless of the forced one
+    pub fn rom_palette(&self) -> &[u32; 16] {
+        &self.palette
+    }
+
     pub fn set_palette(&mut self, buf: [u32; 16]) {
         self.palette = buf;
     }
 
+    /// Set the palette loaded by `PAL` from `addr`
+    pub fn load_palette(&mut self, addr: u16, buf: [u32; 16]) {
+        self.palette = buf;
+        self.palette_addr = Some(addr);
+        self.palette_frame = self.frames;
+    }
+
+    /// The address and frame of the last `PAL`, if any
+    pub fn palette_source(&self) -> Option<(u16, u64)> {
+        self.palette_addr.map(|addr| (addr, self.palette_frame))
+    }
+
+    pub fn forced_palette(&self) -> Option<&[u32; 16]> {
+        self.forced_palette.as_ref()
+    }
+
+    /// Force a palette that overrides the default and the loaded ones, or remove it
+    pub fn force_palette(&mut self, palette: Option<[u32; 16]>) {
+        self.forced_palette = palette;
+    }
+
+    /// The number of rendered frames
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    /// Compose the frame as palette indices, using the background color where the foreground is transparent
+    pub fn frame(&self) -> Vec<u8> {
+        self.fg.iter().map(|&fg| if fg != 0 { fg } else { self.bg }).collect()
+    }
+
+    /// A stable 64 bit FNV-1a hash of the indexed foreground, the background color and the active palette,
+    /// which identifies the rendered frame independent of the platform
+    pub fn frame_hash(&self) -> u64 {
+        let palette = self.palette().iter().flat_map(|c| c.to_le_bytes().to_vec());
+        self.fg.iter().cloned()
+            .chain(std::iter::once(self.bg))
+            .chain(palette)
+            .fold(0xCBF2_9CE4_8422_2325, |hash, byte| (hash ^ byte as u64).wrapping_mul(0x0100_0000_01B3))
+    }
+
+    /// Save the frame in its original size of 320x240 as PNG,
+    /// optionally as indexed image with the palette embedded
+    pub fn save_png<P: AsRef<Path>>(&self, path: P, indexed: bool) -> io::Result<()> {
+        let file = BufWriter::new(File::create(path)?);
+        capture::write_png(file, &self.frame(), self.palette(), indexed)
+    }
+
+    pub fn view(&self) -> View {
+        self.view
+    }
+
+    /// Set the debug view, the pixels of the previous one are discarded
+    pub fn set_view(&mut self, view: View) {
+        self.view = view;
+        self.overlap_mask.iter_mut().for_each(|m| *m = false);
+        self.next_draw();
+    }
+
+    /// Start a new `DRW`, which unmarks the pixels of the previous one without clearing the whole mask
+    fn next_draw(&mut self) {
+        self.last_draw = self.last_draw.wrapping_add(1);
+        if self.last_draw == 0 {
+            // Marks of the draw 2^32 draws ago would match again
+            self.last_draw_mask.iter_mut().for_each(|m| *m = 0);
+            self.last_draw = 1;
+        }
+    }
+
+    /// Enable or disable logging the sprites that are drawn
+    pub fn set_draw_log(&mut self, val: bool) {
+        self.draw_log = val;
+        self.draws.clear();
+        self.frame_draws.clear();
+    }
+
+    pub fn draw_log(&self) -> bool {
+        self.draw_log
+    }
+
+    /// Log a sprite drawn from `addr` with the current sprite size and flip flags
+    pub fn log_draw(&mut self, addr: u16, x: i16, y: i16, overlap: bool) {
+        if self.draw_log {
+            let (w, h, hflip, vflip) = (self.spritew, self.spriteh, self.hflip, self.vflip);
+            self.draws.push(DrawCall { addr, x, y, w, h, hflip, vflip, overlap });
+        }
+    }
+
+    /// The sprites drawn in the current frame so far
+    pub fn draws(&self) -> &[DrawCall] {
+        &self.draws
+    }
+
+    /// The sprites drawn in the last rendered frame
+    pub fn frame_draws(&self) -> &[DrawCall] {
+        &self.frame_draws
+    }
+
+    /// Enable or disable measuring the time spent in `draw`
+    pub fn set_profile(&mut self, val: bool) {
+        self.profile = val;
+        self.draw_time = Duration::ZERO;
+    }
+
+    /// The time spent drawing sprites since profiling was enabled
+    pub fn draw_time(&self) -> Duration {
+        self.draw_time
+    }
+
     /// Draw a sprite to the foreground
     pub fn draw(&mut self, x: i16, y: i16, buf: &[u8]) -> bool {
-        let mut overlap = false;
+        if !self.profile {
+            return self.blit(x, y, buf);
+        }
+        let start = Instant::now();
+        let overlap = self.blit(x, y, buf);
+        self.draw_time += start.elapsed();
+        overlap
+    }
+
+    /// Draw a sprite to the foreground and return whether it overlapped with a pixel that was already drawn,
+    /// the sprite is clipped to the screen first and then copied row by row
+    fn blit(&mut self, x: i16, y: i16, buf: &[u8]) -> bool {
+        if self.view != View::Normal {
+            self.next_draw();
+        }
 
+        // The visible pixel columns and rows in sprite coordinates
         let (x, y) = (x as i32, y as i32);
         let (w, h) = (self.spritew as i32, self.spriteh as i32);
+        let (left, right) = (max(0, -x), min(w * 2, 320 - x));
+        let (top, bottom) = (max(0, -y), min(h, 240 - y));
+        if left >= right || top >= bottom {
+            return false;
+        }
 
-        for j in max(0, y)..min(240, y + h) {
-            for i in (max(-1, x)..min(320, x + w * 2)).filter(|z| (z - x) % 2 == 0) {
-                let a = if !self.hflip { (i - x) / 2 } else { w - (i - x) / 2 - 1 } as usize;
-                let b = if !self.vflip { j - y } else { h - (j - y) - 1 } as usize;
-                let (mut high, mut low) = half_bytes(buf[a + b * w as usize]);
-                if self.hflip { swap(&mut high, &mut low) };
-                let p = i + j * 320;
-                if i >= 0 && high != 0 {
-                    let p = p as usize;
-                    if self.fg[p] != 0 {
-                        overlap = true;
+        let mut overlap = false;
+        let mut row = [0; 2 * 255];
+        for j in top..bottom {
+            let b = if !self.vflip { j } else { h - j - 1 } as usize * w as usize;
+            sprite_row(&buf[b..b + w as usize], self.hflip, &mut row[..w as usize * 2]);
+            let pixels = &row[left as usize..right as usize];
+
+            let p = ((y + j) * 320 + x + left) as usize;
+            if self.view == View::Normal {
+                for (fg, &color) in self.fg[p..p + pixels.len()].iter_mut().zip(pixels.iter()) {
+                    if color != 0 {
+                        overlap |= *fg != 0;
+                        *fg = color;
                     }
-                    self.fg[p] = high;
                 }
-                if i < 319 && low != 0 {
-                    let p = (p + 1) as usize;
-                    if self.fg[p] != 0 {
-                        overlap = true;
+            } else {
+                for (i, &color) in pixels.iter().enumerate() {
+                    if color != 0 {
+                        overlap |= self.plot(p + i, color);
                     }
-                    self.fg[p] = low;
                 }
             }
         }
@@ -123,14 +377,265 @@ impl Gpu {
         overlap
     }
 
+    /// Write a pixel to the foreground and return whether it overlapped,
+    /// marking it for the debug views if one is active
+    fn plot(&mut self, p: usize, color: u8) -> bool {
+        let overlap = self.fg[p] != 0;
+        self.fg[p] = color;
+        if self.view != View::Normal {
+            self.last_draw_mask[p] = self.last_draw;
+            self.overlap_mask[p] |= overlap;
+        }
+        overlap
+    }
+
     /// Render the frame to a buffer of the size 320x240
     pub fn render(&mut self, buffer: &mut [u32]) {
-        let bgc = self.palette[self.bg as usize];
+        let palette = *self.palette();
+        let bgc = palette[self.bg as usize];
 
         for (buf, &fg) in buffer.iter_mut().zip(self.fg.iter()) {
-            *buf = if fg != 0 { self.palette[fg as usize] } else { bgc };
+            *buf = if fg != 0 { palette[fg as usize] } else { bgc };
         }
 
+        match self.view {
+            View::Normal => {},
+            View::Overlap => {
+                for (buf, &overlap) in buffer.iter_mut().zip(self.overlap_mask.iter()) {
+                    if overlap {
+                        *buf = (*buf >> 1 & 0x7F7F7F) | 0x800000;
+                    }
+                }
+            },
+            View::Layers => {
+                // Show the background as checkerboard of its color and a darker shade of it
+                for (i, (buf, &fg)) in buffer.iter_mut().zip(self.fg.iter()).enumerate() {
+                    if fg == 0 && (i % 320 / 8 + i / 320 / 8) % 2 == 1 {
+                        *buf = (bgc >> 2 & 0x3F3F3F) + 0x202020;
+                    }
+                }
+            },
+            View::LastDraw => {
+                // Invert the pixels of the last sprite every 8 frames
+                if self.frames & 8 == 0 {
+                    for (buf, &draw) in buffer.iter_mut().zip(self.last_draw_mask.iter()) {
+                        if draw == self.last_draw {
+                            *buf ^= 0xFFFFFF;
+                        }
+                    }
+                }
+            },
+        }
+        if self.view != View::Normal {
+            self.overlap_mask.iter_mut().for_each(|m| *m = false);
+        }
+
+        if self.draw_log {
+            self.frame_draws = mem::take(&mut self.draws);
+        }
+
+        self.frames += 1;
         self.vblank = true;
     }
 }
+
+impl Default for Gpu {
+    fn default() -> Gpu {
+        Gpu::new()
+    }
+}
+
+/// Decode the pixels of a sprite row into `pixels`, a flipped row is the unflipped one reversed
+fn sprite_row(line: &[u8], hflip: bool, pixels: &mut [u8]) {
+    for (pair, &byte) in pixels.chunks_exact_mut(2).zip(line.iter()) {
+        pair[0] = byte >> 4;
+        pair[1] = byte & 0x0F;
+    }
+    if hflip {
+        pixels.reverse();
+    }
+}
+
+/// Decode sprite data of `w` bytes by `h` rows into palette indices, two pixels per byte
+pub fn sprite_pixels(buf: &[u8], w: u8, h: u8) -> Vec<u8> {
+    buf.iter()
+        .take(w as usize * h as usize)
+        .flat_map(|&byte| { let (high, low) = half_bytes(byte); vec![high, low] })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::mem::swap;
+
+    use rand::rngs::SmallRng;
+    use rand::{Rng, SeedableRng};
+
+    use crate::gpu::*;
+
+    /// The previous implementation of `draw`, which goes through the sprite pixel by pixel
+    fn draw_per_pixel(gpu: &mut Gpu, x: i16, y: i16, buf: &[u8]) -> bool {
+        let mut overlap = false;
+        if gpu.view != View::Normal {
+            gpu.next_draw();
+        }
+
+        let (x, y) = (x as i32, y as i32);
+        let (w, h) = (gpu.spritew as i32, gpu.spriteh as i32);
+
+        for j in max(0, y)..min(240, y + h) {
+            for i in (max(-1, x)..min(320, x + w * 2)).filter(|z| (z - x) % 2 == 0) {
+                let a = if !gpu.hflip { (i - x) / 2 } else { w - (i - x) / 2 - 1 } as usize;
+                let b = if !gpu.vflip { j - y } else { h - (j - y) - 1 } as usize;
+                let (mut high, mut low) = half_bytes(buf[a + b * w as usize]);
+                if gpu.hflip { swap(&mut high, &mut low) };
+                let p = i + j * 320;
+                if i >= 0 && high != 0 {
+                    overlap |= gpu.plot(p as usize, high);
+                }
+                if i < 319 && low != 0 {
+                    overlap |= gpu.plot((p + 1) as usize, low);
+                }
+            }
+        }
+
+        overlap
+    }
+
+    #[test]
+    fn draw_matches_per_pixel_drawing() {
+        let mut rng = SmallRng::seed_from_u64(0);
+        let (mut gpu, mut expected) = (Gpu::new(), Gpu::new());
+        let views = [View::Normal, View::Overlap, View::Layers, View::LastDraw];
+        for i in 0..5000 {
+            if i % 500 == 0 {
+                let view = views[rng.gen_range(0, views.len())];
+                gpu.set_view(view);
+                expected.set_view(view);
+                gpu.clear();
+                expected.clear();
+            }
+            let (w, h) = if rng.gen_bool(0.05) { (rng.gen(), rng.gen()) } else { (rng.gen_range(0, 12), rng.gen_range(0, 12)) };
+            let (hflip, vflip) = (rng.gen(), rng.gen());
+            let (x, y) = if rng.gen_bool(0.05) { (rng.gen(), rng.gen()) } else { (rng.gen_range(-30, 350), rng.gen_range(-30, 270)) };
+            // Sprites with transparent pixels to cover partial overlaps
+            let sprite: Vec<u8> = (0..w as usize * h as usize).map(|_| rng.gen::<u8>() & rng.gen::<u8>()).collect();
+            for gpu in [&mut gpu, &mut expected].iter_mut() {
+                gpu.set_sprite_size(w, h);
+                gpu.set_hflip(hflip);
+                gpu.set_vflip(vflip);
+            }
+
+            let overlap = draw_per_pixel(&mut expected, x, y, &sprite);
+            assert_eq!(gpu.draw(x, y, &sprite), overlap, "{}x{} at {},{} flipped {},{}", w, h, x, y, hflip, vflip);
+            assert!(gpu.fg[..] == expected.fg[..], "{}x{} at {},{} flipped {},{}", w, h, x, y, hflip, vflip);
+            assert_eq!(gpu.overlap_mask, expected.overlap_mask);
+            assert_eq!((&gpu.last_draw_mask, gpu.last_draw), (&expected.last_draw_mask, expected.last_draw));
+        }
+    }
+
+    #[test]
+    fn draw_log_works() {
+        let mut gpu = Gpu::new();
+        let mut buffer = vec![0; 320 * 240];
+        gpu.log_draw(0x100, 1, 2, false);
+        assert!(gpu.draws().is_empty());
+
+        gpu.set_draw_log(true);
+        gpu.set_sprite_size(2, 3);
+        gpu.log_draw(0x100, 1, 2, true);
+        assert_eq!(gpu.draws().len(), 1);
+        gpu.render(&mut buffer);
+        assert!(gpu.draws().is_empty());
+        assert_eq!(gpu.frame_draws(), &[DrawCall {
+            addr: 0x100, x: 1, y: 2, w: 2, h: 3, hflip: false, vflip: false, overlap: true,
+        }]);
+    }
+
+    #[test]
+    fn forced_palette_overrides_loaded_one() {
+        let mut gpu = Gpu::new();
+        let mut buffer = vec![0; 320 * 240];
+        gpu.render(&mut buffer);
+        gpu.load_palette(0x1234, [0x111111; 16]);
+        assert_eq!(gpu.palette_source(), Some((0x1234, 1)));
+
+        gpu.force_palette(named_palette("high-contrast"));
+        assert_eq!(gpu.palette(), &HIGH_CONTRAST_PALETTE);
+        assert_eq!(gpu.rom_palette(), &[0x111111; 16]);
+        gpu.render(&mut buffer);
+        assert_eq!(buffer[0], HIGH_CONTRAST_PALETTE[0]);
+
+        gpu.force_palette(None);
+        gpu.render(&mut buffer);
+        assert_eq!(buffer[0], 0x111111);
+    }
+
+    #[test]
+    fn named_palettes_exist() {
+        for name in &PALETTES {
+            assert!(named_palette(name).is_some());
+        }
+        assert_eq!(named_palette("Default"), Some(DEFAULT_PALETTE));
+        assert_eq!(named_palette("sepia"), None);
+    }
+
+    #[test]
+    fn debug_views_work() {
+        let mut gpu = Gpu::new();
+        let mut buffer = vec![0; 320 * 240];
+        gpu.set_view(View::Overlap);
+        gpu.set_sprite_size(1, 1);
+        gpu.draw(0, 0, &[0x22]);
+        assert!(!gpu.draw(2, 0, &[0x22]));
+        assert!(gpu.draw(1, 0, &[0x33]));
+        gpu.render(&mut buffer);
+        let gray = DEFAULT_PALETTE[2];
+        let red = (DEFAULT_PALETTE[3] >> 1 & 0x7F7F7F) | 0x800000;
+        assert_eq!(&buffer[..4], &[gray, red, red, gray]);
+        // The overlap is only shown in the frame it happened in
+        gpu.render(&mut buffer);
+        assert_eq!(&buffer[..4], &[gray, DEFAULT_PALETTE[3], DEFAULT_PALETTE[3], gray]);
+
+        gpu.set_view(View::LastDraw);
+        gpu.draw(0, 1, &[0x22]);
+        gpu.draw(4, 1, &[0x33]);
+        gpu.render(&mut buffer);
+        assert_eq!(&buffer[320..326], &[gray, gray, 0, 0, DEFAULT_PALETTE[3] ^ 0xFFFFFF, DEFAULT_PALETTE[3] ^ 0xFFFFFF]);
+        // A mark of the draw 2^32 draws ago is not shown when the counter wraps around
+        gpu.last_draw = u32::MAX;
+        gpu.last_draw_mask[5] = 1;
+        gpu.draw(0, 1, &[0x22]);
+        gpu.render(&mut buffer);
+        assert_eq!((gpu.last_draw, buffer[5], buffer[320]), (1, DEFAULT_PALETTE[0], DEFAULT_PALETTE[2] ^ 0xFFFFFF));
+
+        gpu.set_view(View::Layers);
+        gpu.render(&mut buffer);
+        assert_eq!(buffer[0], gray);
+        assert_eq!(buffer[7], 0);
+        assert_eq!(buffer[8], 0x202020);
+    }
+
+    #[test]
+    fn frame_hash_works() {
+        let mut gpu = Gpu::new();
+        let empty = gpu.frame_hash();
+        assert_eq!(empty, Gpu::new().frame_hash());
+
+        gpu.set_sprite_size(1, 1);
+        gpu.draw(0, 0, &[0x10]);
+        let drawn = gpu.frame_hash();
+        assert_ne!(drawn, empty);
+
+        gpu.set_bg(1);
+        assert_ne!(gpu.frame_hash(), drawn);
+        gpu.set_bg(0);
+        gpu.force_palette(named_palette("colorblind"));
+        assert_ne!(gpu.frame_hash(), drawn);
+    }
+
+    #[test]
+    fn sprite_pixels_works() {
+        assert_eq!(sprite_pixels(&[0x12, 0x34, 0x56], 1, 2), vec![1, 2, 3, 4]);
+    }
+}
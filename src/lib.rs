@@ -0,0 +1,9 @@
+//! The emulator core, shared by the `rchip16` frontend, the benchmarks and the integration tests
+pub mod apu;
+pub mod capture;
+pub mod cpu;
+pub mod golden;
+pub mod gpu;
+pub mod rom;
+#[allow(clippy::useless_transmute, clippy::transmute_ptr_to_ref, clippy::assign_op_pattern, clippy::bool_assert_comparison)]
+pub mod util;
@@ -1,103 +1,289 @@
-mod cpu;
-mod gpu;
-mod apu;
-mod rom;
+mod bench;
 mod debugger;
-mod util;
+mod config;
+mod record;
+mod scale;
+mod viewer;
+mod cli;
+mod tools;
 
-use std::env;
+use std::path::Path;
+use std::process;
 use std::thread;
 use std::time::{Duration, Instant};
 
-use minifb::{Key, Scale, Window, WindowOptions};
+use clap::ArgMatches;
+use minifb::{Key, KeyRepeat, Window, WindowOptions};
+use rchip16::{apu, capture, cpu, gpu, rom, util};
 
 use cpu::*;
 use gpu::*;
 use apu::*;
 use rom::*;
 use debugger::*;
+use config::*;
+use record::*;
+use scale::*;
+use viewer::*;
 use util::*;
 
 fn main() {
-    let rom_file = match env::args().nth(1) {
-        Some(f) => f,
-        None => panic!("No rom file specified"),
+    let matches = cli::app().get_matches();
+    let result = match matches.subcommand() {
+        ("info", Some(matches)) => tools::info(matches),
+        ("pack", Some(matches)) => tools::pack(matches),
+        ("unpack", Some(matches)) => tools::unpack(matches),
+        ("disasm", Some(matches)) => tools::disasm(matches),
+        ("bench", Some(matches)) => bench::bench(matches),
+        _ => run(&matches),
     };
+    if let Err(e) = result {
+        eprintln!("error: {}", e);
+        process::exit(1);
+    }
+}
 
-    let rom = match Rom::load(&rom_file) {
-        Ok(rom) => rom,
-        Err(e) => panic!("Error loading rom file: {}", e),
-    };
+/// Run the rom given on the command line until the window is closed
+fn run(matches: &ArgMatches) -> Result<(), String> {
+    let rom_file = matches.value_of("ROM").unwrap();
+    let rom = Rom::load(rom_file).map_err(|e| format!("Error loading rom file {}: {}", rom_file, e))?;
 
     println!(" version: {}", rom.version());
     println!("    size: {}", rom.size());
     println!("   start: {}", rom.start());
-    let (expected, actual) = rom.checksum();
-    println!("checksum: {} {}", actual, if actual == expected { "OK" } else { "NOT OK" });
+    let (_, actual) = rom.checksum();
+    println!("checksum: {:08X} {}", actual, if rom.checksum_ok() { "OK" } else { "NOT OK" });
 
-    let supported_version = Version(1, 3);
-    if rom.version() > supported_version {
-        println!(" warning: only version {} and lower are supported", supported_version);
+    if rom.version() > SUPPORTED_VERSION {
+        println!(" warning: only version {} and lower are supported", SUPPORTED_VERSION);
     }
 
-    let gpu = Gpu::new();
-    let apu = Apu::new(0.1);
+    let config = match matches.value_of("config") {
+        Some(file) => Config::load(file).map_err(|e| format!("Error loading config file {}: {}", file, e))?,
+        None if Path::new(DEFAULT_CONFIG).exists() => Config::load(DEFAULT_CONFIG)
+            .map_err(|e| format!("Error loading config file {}: {}", DEFAULT_CONFIG, e))?,
+        None => Config::default(),
+    };
+    let settings = config.settings(actual, &cli::overrides(matches))?;
+
+    let headless = matches.is_present("headless");
+    let frames: Option<u64> = cli::value(matches, "frames");
+
+    let mut gpu = Gpu::new();
+    if let Some(palette) = settings.palette {
+        gpu.set_palette(palette);
+    }
+    gpu.force_palette(settings.force_palette);
+    let mut apu = if headless { Apu::silent(settings.volume) } else { Apu::new(settings.volume)? };
+    apu.set_band_limited(!settings.raw_waves);
     let mut cpu = Cpu::new(gpu, apu, &rom);
+    if let Some(seed) = cli::value(matches, "seed") {
+        cpu.set_seed(seed);
+    }
+    cpu.set_decode_cache(!matches.is_present("no-decode-cache"));
+    cpu.set_div_zero(settings.div_zero);
+    cpu.set_stack_guard(settings.stack_guard);
+    cpu.set_strict(settings.strict);
     let mut debugger = Debugger::new();
+    debugger.set_trace(matches.is_present("trace"));
 
-    let limited = !env::args().any(|a| a == "--unlimited");
-    if env::args().any(|a| a == "--break") {
+    let limited = !settings.unlimited;
+    if settings.break_start {
         debugger.set_break();
     }
 
-    let title = format!("rchip16 - {}", rom_file);
-    let options = WindowOptions { scale: Scale::X2, ..WindowOptions::default() };
-    let mut win = Window::new(&title, 320, 240, options).unwrap();
+    let mut win = if headless {
+        None
+    } else {
+        let title = format!("rchip16 - {}", rom_file);
+        let (w, h) = settings.window_size();
+        Some(Window::new(&title, w, h, WindowOptions::default()).map_err(|e| format!("Error creating window: {}", e))?)
+    };
     let mut winbuf = vec![0; 320 * 240];
+    let (w, h) = settings.window_size();
+    let mut scaler = Scaler::new(settings.filter, w, h);
+    let rom_stem = Path::new(rom_file).file_stem().unwrap_or_default().to_os_string();
+
+    let mut recorder = match matches.value_of("record") {
+        Some(file) => Some(start_recording(file, &cpu)?),
+        None => None,
+    };
+    let mut gif = match matches.value_of("gif") {
+        Some(file) => Some(start_gif(file, &cpu)?),
+        None => None,
+    };
+
+    let mut viewer: Option<SpriteViewer> = None;
+    // The index of the forced palette in `PALETTES` selected by F5
+    let mut forced = None;
 
     let frame_instr = 1_000_000 / 60;
-    let frame_time = Duration::from_millis(1_000 / 60);
+    let frame_time = Duration::from_secs_f32(1.0 / 60.0 / settings.speed);
+    let mut frame = 0;
 
-    while win.is_open() && !win.is_key_down(Key::Escape) {
+    while frames.is_none_or(|frames| frame < frames) {
         let start = Instant::now();
 
-        if win.is_key_down(Key::F12) {
-            debugger.set_break();
-        }
+        if let Some(ref win) = win {
+            if !win.is_open() || win.is_key_down(Key::Escape) {
+                break;
+            }
+
+            if win.is_key_down(Key::F12) {
+                debugger.set_break();
+            }
+
+            for &(key, indexed) in &[(Key::F9, false), (Key::F10, true)] {
+                if win.is_key_pressed(key, KeyRepeat::No) {
+                    let path = capture::next_path(&rom_stem, "png");
+                    cpu.gpu().save_png(&path, indexed)
+                        .map_err(|e| format!("Error saving screenshot {}: {}", path.display(), e))?;
+                    println!("screenshot saved to {}", path.display());
+                }
+            }
+
+            if win.is_key_pressed(Key::F8, KeyRepeat::No) {
+                recorder = match recorder.take() {
+                    Some(recorder) => { stop_recording(recorder)?; None },
+                    None => Some(start_recording(capture::next_path(&rom_stem, "y4m"), &cpu)?),
+                };
+            }
+
+            if win.is_key_pressed(Key::F7, KeyRepeat::No) {
+                gif = match gif.take() {
+                    Some(gif) => { stop_gif(gif)?; None },
+                    None => Some(start_gif(capture::next_path(&rom_stem, "gif"), &cpu)?),
+                };
+            }
+
+            if win.is_key_pressed(Key::F4, KeyRepeat::No) {
+                let view = cpu.gpu().view().next();
+                cpu.gpu_mut().set_view(view);
+                println!("view: {:?}", view);
+            }
+
+            if win.is_key_pressed(Key::F5, KeyRepeat::No) {
+                forced = next_palette(forced);
+                cpu.gpu_mut().force_palette(forced.and_then(|i| named_palette(PALETTES[i])));
+                println!("palette: {}", forced.map_or("rom", |i| PALETTES[i]));
+            }
 
-        cpu.set_input(read_input(&win));
+            if win.is_key_pressed(Key::F6, KeyRepeat::No) {
+                viewer = match viewer.take() {
+                    Some(_) => None,
+                    None => Some(SpriteViewer::new()?),
+                };
+                cpu.gpu_mut().set_draw_log(viewer.is_some());
+            }
+
+            cpu.set_input(read_input(win, &settings.controllers));
+        }
 
         for _ in 0..frame_instr {
             debugger.step(&mut cpu);
             cpu.step();
+            for warning in cpu.take_warnings() {
+                eprintln!("warning: {}", warning);
+            }
+            if let Some(trap) = cpu.take_trap() {
+                if headless {
+                    return Err(trap.to_string());
+                }
+                println!("trap: {}", trap);
+                debugger.set_break();
+            }
             if cpu.wait_vblank() {
                 break;
             }
         }
 
         cpu.render(&mut winbuf);
+        frame += 1;
+
+        if let Some(ref mut recorder) = recorder {
+            recorder.record(&mut cpu).map_err(|e| format!("Error recording: {}", e))?;
+        }
+        if let Some(ref mut gif) = gif {
+            gif.record(cpu.gpu()).map_err(|e| format!("Error recording GIF: {}", e))?;
+        }
 
-        win.update_with_buffer(&winbuf).unwrap();
+        if let Some(ref mut win) = win {
+            win.update_with_buffer(scaler.scale(&winbuf)).map_err(|e| format!("Error updating window: {}", e))?;
+        }
+        if let Some(ref mut v) = viewer {
+            v.update(&cpu)?;
+            if !v.is_open() {
+                viewer = None;
+                cpu.gpu_mut().set_draw_log(false);
+            }
+        }
 
         let delta = start.elapsed();
         if limited && delta < frame_time {
             thread::sleep(frame_time - delta);
         }
     }
+
+    if let Some(recorder) = recorder {
+        stop_recording(recorder)?;
+    }
+    if let Some(gif) = gif {
+        stop_gif(gif)?;
+    }
+    if headless {
+        println!("frame hash: {:016X}", cpu.gpu().frame_hash());
+    }
+
+    Ok(())
 }
 
-/// Read inputs for controller 1 & 2
-fn read_input(win: &Window) -> (u8, u8) {
-    use Key::*;
+fn start_recording<P: AsRef<Path>>(path: P, cpu: &Cpu) -> Result<Recorder, String> {
+    let path = path.as_ref();
+    let recorder = Recorder::create(path, cpu.apu().sample_rate())
+        .map_err(|e| format!("Error creating recording {}: {}", path.display(), e))?;
+    println!("recording to {} and {}", path.display(), recorder.audio_path().display());
+    Ok(recorder)
+}
+
+fn stop_recording(recorder: Recorder) -> Result<(), String> {
+    recorder.finish().map_err(|e| format!("Error finishing recording: {}", e))?;
+    println!("recording stopped");
+    Ok(())
+}
+
+/// Cycle through the forced palettes and back to the ones of the rom
+fn next_palette(forced: Option<usize>) -> Option<usize> {
+    match forced {
+        None => Some(0),
+        Some(i) if i + 1 < PALETTES.len() => Some(i + 1),
+        Some(_) => None,
+    }
+}
 
+/// Read inputs for controller 1 & 2
+fn read_input(win: &Window, controllers: &[[Key; 8]; 2]) -> (u8, u8) {
     // Keys are set in the order Up, Down, Left, Right, Select, Start, A, B
     let (mut one, mut two) = (0, 0);
-    for (i, &key) in [Up, Down, Left, Right, RightShift, Enter, N, M].iter().enumerate() {
+    for (i, &key) in controllers[0].iter().enumerate() {
         set_bitflag(&mut one, i as u8, win.is_key_down(key));
     }
-    for (i, &key) in [W, S, A, D, LeftShift, Tab, X, C].iter().enumerate() {
+    for (i, &key) in controllers[1].iter().enumerate() {
         set_bitflag(&mut two, i as u8, win.is_key_down(key));
     }
 
     (one, two)
 }
+
+fn start_gif<P: AsRef<Path>>(path: P, cpu: &Cpu) -> Result<GifRecorder, String> {
+    let path = path.as_ref();
+    let gif = GifRecorder::create(path, cpu.gpu().palette())
+        .map_err(|e| format!("Error creating GIF {}: {}", path.display(), e))?;
+    println!("recording GIF to {}", path.display());
+    Ok(gif)
+}
+
+fn stop_gif(gif: GifRecorder) -> Result<(), String> {
+    gif.finish().map_err(|e| format!("Error finishing GIF: {}", e))?;
+    println!("GIF recording stopped");
+    Ok(())
+}
@@ -0,0 +1,271 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use std::borrow::Cow;
+
+use gif::{DisposalMethod, Encoder, ExtensionData, Repeat};
+use hound::{SampleFormat, WavSpec, WavWriter};
+
+use crate::capture::{self, HEIGHT, WIDTH};
+use crate::cpu::Cpu;
+use crate::gpu::Gpu;
+
+/// The number of frames per second in emulated time
+const FRAME_RATE: u64 = 60;
+
+/// Records every frame as uncompressed Y4M video alongside a WAV file of the audio output,
+/// both are timed by emulated frames so no frames are dropped or duplicated
+pub struct Recorder {
+    video: BufWriter<File>,
+    audio: WavWriter<BufWriter<File>>,
+    audio_path: PathBuf,
+    sample_rate: u32,
+    frames: u64,
+    samples: u64,
+}
+
+impl Recorder {
+    /// Create the video file at `path` and the audio file next to it with the extension `wav`
+    pub fn create<P: AsRef<Path>>(path: P, sample_rate: u32) -> io::Result<Recorder> {
+        let path = path.as_ref();
+        let mut video = BufWriter::new(File::create(path)?);
+        writeln!(video, "YUV4MPEG2 W{} H{} F{}:1 Ip A1:1 C444", WIDTH, HEIGHT, FRAME_RATE)?;
+
+        let audio_path = path.with_extension("wav");
+        let spec = WavSpec { channels: 1, sample_rate, bits_per_sample: 16, sample_format: SampleFormat::Int };
+        let audio = WavWriter::create(&audio_path, spec).map_err(to_io_error)?;
+
+        Ok(Recorder { video, audio, audio_path, sample_rate, frames: 0, samples: 0 })
+    }
+
+    /// The path of the audio file
+    pub fn audio_path(&self) -> &Path {
+        &self.audio_path
+    }
+
+    /// Record the current frame and the audio samples of its duration
+    pub fn record(&mut self, cpu: &mut Cpu) -> io::Result<()> {
+        let gpu = cpu.gpu();
+        self.write_frame(&gpu.frame(), gpu.palette())?;
+
+        // Compute the total instead of the samples per frame to avoid drifting for fractional amounts
+        self.frames += 1;
+        let total = self.frames * self.sample_rate as u64 / FRAME_RATE;
+        let samples = cpu.apu_mut().samples((total - self.samples) as usize);
+        self.samples = total;
+        for sample in samples {
+            self.audio.write_sample((sample * i16::MAX as f32) as i16).map_err(to_io_error)?;
+        }
+
+        Ok(())
+    }
+
+    /// Write a frame of palette indices in the planar YCbCr 4:4:4 format
+    fn write_frame(&mut self, frame: &[u8], palette: &[u32; 16]) -> io::Result<()> {
+        let mut planes = [[0; 16]; 3];
+        for (i, &color) in palette.iter().enumerate() {
+            let [y, cb, cr] = ycbcr(color);
+            planes[0][i] = y;
+            planes[1][i] = cb;
+            planes[2][i] = cr;
+        }
+
+        self.video.write_all(b"FRAME\n")?;
+        for plane in &planes {
+            let data: Vec<u8> = frame.iter().map(|&p| plane[p as usize]).collect();
+            self.video.write_all(&data)?;
+        }
+        Ok(())
+    }
+
+    /// Flush both files and finalize the WAV header
+    pub fn finish(mut self) -> io::Result<()> {
+        self.video.flush()?;
+        self.audio.finalize().map_err(to_io_error)
+    }
+}
+
+/// Records frames as animated GIF using the chip16 palette as is, so no quantisation is needed.
+/// Only the region that changed since the previous frame is encoded and unchanged frames extend the delay.
+pub struct GifRecorder {
+    encoder: Encoder<BufWriter<File>>,
+    /// The palette of the first frame, later palettes are written as local palette
+    global: [u32; 16],
+    /// The last frame written to the file
+    previous: Option<(Vec<u8>, [u32; 16])>,
+    /// The last frame and its start time, which is written once its delay is known
+    pending: Option<(Vec<u8>, [u32; 16], u64)>,
+    frames: u64,
+}
+
+/// The shortest delay in centiseconds, viewers slow down shorter delays
+const MIN_DELAY: u64 = 2;
+
+impl GifRecorder {
+    /// Create the GIF file at `path` using `palette` as global palette
+    pub fn create<P: AsRef<Path>>(path: P, palette: &[u32; 16]) -> io::Result<GifRecorder> {
+        let file = BufWriter::new(File::create(path)?);
+        let mut encoder = Encoder::new(file, WIDTH as u16, HEIGHT as u16, &gif_palette(palette))?;
+        encoder.write_extension(ExtensionData::Repetitions(Repeat::Infinite))?;
+        Ok(GifRecorder { encoder, global: *palette, previous: None, pending: None, frames: 0 })
+    }
+
+    /// Record the current frame, a frame that would be shown shorter than `MIN_DELAY` is merged into the next one
+    pub fn record(&mut self, gpu: &Gpu) -> io::Result<()> {
+        let (frame, palette) = (gpu.frame(), *gpu.palette());
+        let time = self.time();
+        self.frames += 1;
+
+        match self.pending {
+            Some((ref pending, ref pending_palette, _)) if *pending == frame && *pending_palette == palette => {},
+            Some((_, _, start)) if time - start < MIN_DELAY => self.pending = Some((frame, palette, start)),
+            _ => {
+                self.write_pending(time)?;
+                self.pending = Some((frame, palette, time));
+            },
+        }
+        Ok(())
+    }
+
+    /// Write the last frame and close the file
+    pub fn finish(mut self) -> io::Result<()> {
+        let end = self.time();
+        self.write_pending(end)
+    }
+
+    /// The time in centiseconds at which the next frame starts, computed from the number of frames
+    /// so the remainder of each delay carries over and the recording does not drift
+    fn time(&self) -> u64 {
+        self.frames * 100 / FRAME_RATE
+    }
+
+    fn write_pending(&mut self, end: u64) -> io::Result<()> {
+        let (frame, palette, start) = match self.pending.take() {
+            Some(pending) => pending,
+            None => return Ok(()),
+        };
+
+        // A frame that equals the previous one still needs a pixel to carry its delay
+        let (left, top, right, bottom) = match self.previous {
+            Some((ref previous, ref previous_palette)) if *previous_palette == palette => {
+                changed_region(previous, &frame).unwrap_or((0, 0, 1, 1))
+            },
+            _ => (0, 0, WIDTH, HEIGHT),
+        };
+
+        let mut buffer = Vec::with_capacity((right - left) * (bottom - top));
+        for row in frame.chunks(WIDTH).take(bottom).skip(top) {
+            buffer.extend_from_slice(&row[left..right]);
+        }
+
+        self.encoder.write_frame(&gif::Frame {
+            delay: (end - start).clamp(MIN_DELAY, u16::MAX as u64) as u16,
+            left: left as u16,
+            top: top as u16,
+            width: (right - left) as u16,
+            height: (bottom - top) as u16,
+            dispose: DisposalMethod::Keep,
+            palette: if palette != self.global { Some(gif_palette(&palette)) } else { None },
+            buffer: Cow::Owned(buffer),
+            ..gif::Frame::default()
+        })?;
+        self.previous = Some((frame, palette));
+        Ok(())
+    }
+}
+
+/// Convert a palette to the RGB triples used by GIF
+fn gif_palette(palette: &[u32; 16]) -> Vec<u8> {
+    palette.iter().flat_map(|&c| capture::rgb(c).to_vec()).collect()
+}
+
+/// Find the region `(left, top, right, bottom)` in which two frames differ
+fn changed_region(a: &[u8], b: &[u8]) -> Option<(usize, usize, usize, usize)> {
+    let mut region: Option<(usize, usize, usize, usize)> = None;
+    for (i, _) in a.iter().zip(b).enumerate().filter(|(_, (a, b))| a != b) {
+        let (x, y) = (i % WIDTH, i / WIDTH);
+        region = Some(match region {
+            Some((left, top, right, bottom)) => (left.min(x), top.min(y), right.max(x + 1), bottom.max(y + 1)),
+            None => (x, y, x + 1, y + 1),
+        });
+    }
+    region
+}
+
+/// Convert a color of the form `0xRRGGBB` to studio range YCbCr as defined by BT.601
+fn ycbcr(color: u32) -> [u8; 3] {
+    let [r, g, b] = capture::rgb(color);
+    let (r, g, b) = (r as f32, g as f32, b as f32);
+    let y = 16.0 + (65.481 * r + 128.553 * g + 24.966 * b) / 255.0;
+    let cb = 128.0 + (-37.797 * r - 74.203 * g + 112.0 * b) / 255.0;
+    let cr = 128.0 + (112.0 * r - 93.786 * g - 18.214 * b) / 255.0;
+    [y.round() as u8, cb.round() as u8, cr.round() as u8]
+}
+
+fn to_io_error(e: hound::Error) -> io::Error {
+    match e {
+        hound::Error::IoError(e) => e,
+        e => io::Error::other(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::record::*;
+
+    #[test]
+    fn ycbcr_works() {
+        assert_eq!(ycbcr(0x000000), [16, 128, 128]);
+        assert_eq!(ycbcr(0xFFFFFF), [235, 128, 128]);
+        assert_eq!(ycbcr(0xFF0000), [81, 90, 240]);
+    }
+
+    #[test]
+    fn gif_recorder_works() {
+        let path = std::env::temp_dir().join(format!("rchip16-gif-{}.gif", std::process::id()));
+        let mut gpu = Gpu::new();
+        let mut gif = GifRecorder::create(&path, gpu.palette()).unwrap();
+        gpu.set_sprite_size(1, 1);
+        for _ in 0..3 {
+            gif.record(&gpu).unwrap();
+        }
+        gpu.draw(10, 20, &[0x12]);
+        gif.record(&gpu).unwrap();
+        gif.record(&gpu).unwrap();
+        let mut palette = *gpu.palette();
+        palette[1] = 0xFFFFFF;
+        gpu.set_palette(palette);
+        gif.record(&gpu).unwrap();
+        gpu.draw(100, 100, &[0x12]);
+        gif.record(&gpu).unwrap();
+        // Shown for 1 cs, so it is merged into the next frame
+        gpu.draw(30, 40, &[0x12]);
+        gif.record(&gpu).unwrap();
+        gif.finish().unwrap();
+
+        let mut decoder = gif::Decoder::new(File::open(&path).unwrap()).read_info().unwrap();
+        let mut frames = Vec::new();
+        while let Some(frame) = decoder.read_next_frame().unwrap() {
+            frames.push((frame.left, frame.top, frame.width, frame.height, frame.delay, frame.palette.is_some()));
+        }
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(frames, vec![
+            (0, 0, 320, 240, 5, false),
+            (10, 20, 2, 1, 3, false),
+            (0, 0, 320, 240, 2, true),
+            (30, 40, 72, 61, 3, true),
+        ]);
+    }
+
+    #[test]
+    fn changed_region_works() {
+        let a = vec![0; WIDTH * HEIGHT];
+        let mut b = a.clone();
+        assert_eq!(changed_region(&a, &b), None);
+        b[10 + 20 * WIDTH] = 1;
+        b[15 + 5 * WIDTH] = 2;
+        assert_eq!(changed_region(&a, &b), Some((10, 5, 16, 21)));
+    }
+}
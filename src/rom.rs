@@ -2,7 +2,9 @@ use std::fmt;
 use std::fs::File;
 use std::path::Path;
 use std::io::prelude::*;
-use std::io::Result;
+use std::io::{Error, ErrorKind, Result};
+use std::result;
+use std::str::FromStr;
 
 use crate::util::*;
 
@@ -15,46 +17,80 @@ impl fmt::Display for Version {
     }
 }
 
+/// The newest specification version that is supported
+pub const SUPPORTED_VERSION: Version = Version(1, 3);
+
+/// The published versions of the specification that can appear in a `CH16` header
+pub static PUBLISHED_VERSIONS: [Version; 4] = [Version(1, 0), Version(1, 1), Version(1, 2), Version(1, 3)];
+
+impl Version {
+    /// Check if the version is a published specification version
+    pub fn is_known(self) -> bool {
+        PUBLISHED_VERSIONS.contains(&self)
+    }
+}
+
+impl FromStr for Version {
+    type Err = String;
+
+    /// Parse a version given as `MAJOR.MINOR` (e.g. `1.3`)
+    fn from_str(s: &str) -> result::Result<Version, String> {
+        let mut parts = s.splitn(2, '.').map(|p| p.parse::<u8>().ok().filter(|&n| n < 16));
+        match (parts.next().flatten(), parts.next().flatten()) {
+            (Some(major), Some(minor)) => Ok(Version(major, minor)),
+            _ => Err(format!("invalid version '{}'", s)),
+        }
+    }
+}
+
 pub struct Rom {
+    header: bool,
     version: Version,
     size: u32,
     start: u16,
     checksum: u32,
+    /// All data following the header, even if it differs from the declared size
     rom: Vec<u8>,
 }
 
 impl Rom {
     pub fn load<P: AsRef<Path>>(path: P) -> Result<Rom> {
         let mut file = File::open(path)?;
-        let mut magic = [0; 4];
-        file.read_exact(&mut magic)?;
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)?;
 
         // check the rom file for the magic number
-        if &magic == b"CH16" {
-            let mut header = [0; 12];
-            file.read_exact(&mut header)?;
-            let size = *deserialize(&header[2..]);
-            let mut rom = vec![0; size as usize];
-            file.read_exact(rom.as_mut_slice())?;
-            Ok(Rom {
+        let rom = if data.len() >= 16 && &data[..4] == b"CH16" {
+            let header = &data[4..16];
+            Rom {
+                header: true,
                 version: { let (h, l) = half_bytes(header[1]); Version(h, l) },
-                size,
+                size: *deserialize(&header[2..]),
                 start: *deserialize(&header[6..]),
                 checksum: *deserialize(&header[8..]),
-                rom,
-            })
+                rom: data[16..].to_vec(),
+            }
         } else {
-            let mut rom = vec![0; 4];
-            rom.copy_from_slice(&magic);
-            let len = file.read_to_end(&mut rom)?;
-            Ok(Rom {
+            Rom {
+                header: false,
                 version: Version(0, 0),
-                size: len as u32 + 4,
+                size: data.len() as u32,
                 start: 0,
                 checksum: 0,
-                rom,
-            })
+                rom: data,
+            }
+        };
+
+        if rom.rom.len() > 0x10000 {
+            return Err(Error::new(ErrorKind::InvalidData, "the rom does not fit into 64 KB of memory"));
         }
+
+        Ok(rom)
+    }
+
+    /// Check if the rom starts with a `CH16` header
+    pub fn has_header(&self) -> bool {
+        self.header
     }
 
     pub fn version(&self) -> Version {
@@ -65,6 +101,11 @@ impl Rom {
         self.size
     }
 
+    /// The size of the data following the header
+    pub fn file_size(&self) -> u32 {
+        self.rom.len() as u32
+    }
+
     pub fn start(&self) -> u16 {
         self.start
     }
@@ -76,4 +117,213 @@ impl Rom {
     pub fn rom(&self) -> &Vec<u8> {
         &self.rom
     }
+
+    /// Check if the declared size matches the size of the file
+    pub fn size_ok(&self) -> bool {
+        self.size == self.file_size()
+    }
+
+    /// Check if the start address lies inside the rom
+    pub fn start_ok(&self) -> bool {
+        (self.start as u32) < self.size
+    }
+
+    /// Check if the version in the header is a published one, roms without a header are always valid
+    pub fn version_ok(&self) -> bool {
+        !self.header || self.version.is_known()
+    }
+
+    /// Check if the checksum in the header matches the data, roms without a header are always valid
+    pub fn checksum_ok(&self) -> bool {
+        let (expected, actual) = self.checksum();
+        !self.header || expected == actual
+    }
+
+    /// Check if all header fields are valid
+    pub fn is_valid(&self) -> bool {
+        self.size_ok() && self.start_ok() && self.version_ok() && self.checksum_ok()
+    }
+
+    /// Recompute the size and checksum of the header from the data
+    pub fn repair(&mut self) {
+        self.header = true;
+        self.size = self.file_size();
+        self.checksum = crc32(&self.rom);
+    }
+
+    /// Serialize the rom including its `CH16` header, roms without a header are written as is
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(16 + self.rom.len());
+        if self.header {
+            data.extend_from_slice(b"CH16");
+            data.push(0);
+            data.push(self.version.0 << 4 | self.version.1);
+            data.extend_from_slice(serialize(&self.size));
+            data.extend_from_slice(serialize(&self.start));
+            data.extend_from_slice(serialize(&self.checksum));
+        }
+        data.extend_from_slice(&self.rom);
+        data
+    }
+
+    /// Write the rom to a file
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        File::create(path)?.write_all(&self.to_bytes())
+    }
+}
+
+/// Builder for a rom with a `CH16` header
+pub struct RomBuilder {
+    version: Version,
+    start: u16,
+    rom: Vec<u8>,
+}
+
+impl RomBuilder {
+    /// Wrap the raw binary `rom`, by default the version is 1.3 and the start address is 0
+    pub fn new(rom: Vec<u8>) -> RomBuilder {
+        RomBuilder { version: SUPPORTED_VERSION, start: 0, rom }
+    }
+
+    pub fn version(mut self, version: Version) -> RomBuilder {
+        self.version = version;
+        self
+    }
+
+    pub fn start(mut self, start: u16) -> RomBuilder {
+        self.start = start;
+        self
+    }
+
+    /// Build the rom, the size and checksum are computed from the data
+    pub fn build(self) -> Rom {
+        let mut rom = Rom {
+            header: true,
+            version: self.version,
+            size: 0,
+            start: self.start,
+            checksum: 0,
+            rom: self.rom,
+        };
+        rom.repair();
+        rom
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+
+    use crate::rom::*;
+
+    fn load(name: &str, data: &[u8]) -> Rom {
+        let path = env::temp_dir().join(format!("rchip16-rom-{}-{}", name, std::process::id()));
+        fs::write(&path, data).unwrap();
+        let rom = Rom::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        rom
+    }
+
+    fn header(version: u8, size: u32, start: u16, checksum: u32) -> Vec<u8> {
+        let mut data = b"CH16\0".to_vec();
+        data.push(version);
+        data.extend_from_slice(serialize(&size));
+        data.extend_from_slice(serialize(&start));
+        data.extend_from_slice(serialize(&checksum));
+        data
+    }
+
+    #[test]
+    fn valid_header_works() {
+        let code = [0x01, 0x00, 0x00, 0x00, 0x10, 0x00, 0x00, 0x00];
+        let mut data = header(0x13, 8, 4, crc32(&code));
+        data.extend_from_slice(&code);
+        let rom = load("valid", &data);
+        assert!(rom.has_header());
+        assert_eq!(rom.version(), Version(1, 3));
+        assert_eq!(rom.start(), 4);
+        assert_eq!(rom.rom(), &code.to_vec());
+        assert!(rom.is_valid());
+    }
+
+    #[test]
+    fn invalid_header_is_detected() {
+        let code = [0x01, 0x00, 0x00, 0x00];
+        let mut data = header(0x20, 8, 8, 0);
+        data.extend_from_slice(&code);
+        let rom = load("invalid", &data);
+        assert!(!rom.size_ok());
+        assert!(!rom.start_ok());
+        assert!(!rom.version().is_known());
+        assert!(!rom.checksum_ok());
+        assert!(!rom.is_valid());
+    }
+
+    #[test]
+    fn builder_works() {
+        let code = vec![0x01, 0x00, 0x00, 0x00, 0x10, 0x00, 0x00, 0x00];
+        let rom = RomBuilder::new(code.clone()).version(Version(1, 1)).start(4).build();
+        let mut data = header(0x11, 8, 4, crc32(&code));
+        data.extend_from_slice(&code);
+        assert_eq!(rom.to_bytes(), data);
+
+        let loaded = load("builder", &rom.to_bytes());
+        assert_eq!(loaded.version(), Version(1, 1));
+        assert_eq!(loaded.start(), 4);
+        assert!(loaded.is_valid());
+    }
+
+    #[test]
+    fn repair_works() {
+        let code = [0x01, 0x00, 0x00, 0x00];
+        let mut data = header(0x13, 8, 0, 0);
+        data.extend_from_slice(&code);
+        let mut rom = load("repair", &data);
+        assert!(!rom.is_valid());
+        rom.repair();
+        assert!(rom.is_valid());
+        assert_eq!(rom.to_bytes()[6..10], serialize(&4u32)[..]);
+    }
+
+    #[test]
+    fn repair_keeps_data_past_the_declared_size() {
+        let code = [0x01, 0x00, 0x00, 0x00, 0x10, 0x00, 0x00, 0x00];
+        let mut data = header(0x13, 4, 0, crc32(&code[..4]));
+        data.extend_from_slice(&code);
+        let mut rom = load("too-small", &data);
+        assert_eq!(rom.rom(), &code.to_vec());
+        assert!(!rom.size_ok());
+        rom.repair();
+        assert!(rom.is_valid());
+
+        let mut expected = header(0x13, 8, 0, crc32(&code));
+        expected.extend_from_slice(&code);
+        assert_eq!(rom.to_bytes(), expected);
+    }
+
+    #[test]
+    fn only_published_versions_are_known() {
+        assert!(PUBLISHED_VERSIONS.iter().all(|v| v.is_known()));
+        assert!(!Version(0, 9).is_known());
+        assert!(!Version(1, 4).is_known());
+        assert!(!Version(0, 0).is_known());
+    }
+
+    #[test]
+    fn version_from_str_works() {
+        assert_eq!("1.3".parse(), Ok(Version(1, 3)));
+        assert_eq!("0.7".parse(), Ok(Version(0, 7)));
+        assert!("1".parse::<Version>().is_err());
+        assert!("1.16".parse::<Version>().is_err());
+    }
+
+    #[test]
+    fn raw_rom_works() {
+        let code = [0x01, 0x00, 0x00, 0x00];
+        let rom = load("raw", &code);
+        assert!(!rom.has_header());
+        assert_eq!(rom.size(), 4);
+        assert!(rom.is_valid());
+    }
 }
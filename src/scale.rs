@@ -0,0 +1,287 @@
+use std::str::FromStr;
+
+use crate::capture::{HEIGHT, WIDTH};
+
+/// A filter that is applied to the frame before it is scaled to the window
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum Filter {
+    /// Nearest neighbour scaling only
+    None,
+    /// Scale2x / EPX, which rounds diagonal edges without blending colors
+    Scale2x,
+    /// A compact variant of hq2x, which blends edges detected by their difference in YUV
+    Hq2x,
+    /// Darkens every second line like the scanlines of a CRT
+    Scanlines,
+}
+
+pub static FILTERS: [&str; 4] = ["none", "scale2x", "hq2x", "scanlines"];
+
+impl FromStr for Filter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Filter, String> {
+        Ok(match s.to_lowercase().as_str() {
+            "none" => Filter::None,
+            "scale2x" | "epx" => Filter::Scale2x,
+            "hq2x" => Filter::Hq2x,
+            "scanlines" | "crt" => Filter::Scanlines,
+            _ => return Err(format!("Unknown filter {} (expected one of {})", s, FILTERS.join(", "))),
+        })
+    }
+}
+
+impl Filter {
+    /// The factor by which the filter enlarges the frame
+    fn factor(self) -> usize {
+        match self {
+            Filter::None => 1,
+            Filter::Scale2x | Filter::Hq2x | Filter::Scanlines => 2,
+        }
+    }
+
+    /// Apply the filter to a frame of the size `w`x`h`, writing the enlarged frame to `dst`
+    fn apply(self, src: &[u32], w: usize, h: usize, dst: &mut Vec<u32>) {
+        dst.resize(w * h * self.factor() * self.factor(), 0);
+        match self {
+            Filter::None => dst.copy_from_slice(src),
+            Filter::Scale2x => quadrants(src, w, h, scale2x, dst),
+            Filter::Hq2x => quadrants(src, w, h, hq2x, dst),
+            Filter::Scanlines => scanlines(src, w, h, dst),
+        }
+    }
+}
+
+/// Scales frames of the size 320x240 to the size of the window,
+/// using the largest integer factor that fits and letterboxing the rest
+pub struct Scaler {
+    filter: Filter,
+    width: usize,
+    height: usize,
+    /// The filtered frame, which is reused for every frame
+    filtered: Vec<u32>,
+    buffer: Vec<u32>,
+}
+
+impl Scaler {
+    pub fn new(filter: Filter, width: usize, height: usize) -> Scaler {
+        Scaler { filter, width, height, filtered: Vec::new(), buffer: vec![0; width * height] }
+    }
+
+    /// The integer factor the frame is scaled by and the filter that is applied before.
+    /// Filters that enlarge the frame round the factor down to a multiple of theirs and are skipped if it is too small.
+    fn factor(&self) -> (usize, Filter) {
+        let factor = (self.width / WIDTH).min(self.height / HEIGHT).max(1);
+        let f = self.filter.factor();
+        if factor >= f {
+            (factor - factor % f, self.filter)
+        } else {
+            (factor, Filter::None)
+        }
+    }
+
+    /// Scale a frame and return the buffer of the size of the window
+    pub fn scale(&mut self, src: &[u32]) -> &[u32] {
+        let (factor, filter) = self.factor();
+        let filtered = match filter {
+            Filter::None => src,
+            _ => {
+                filter.apply(src, WIDTH, HEIGHT, &mut self.filtered);
+                &self.filtered
+            },
+        };
+        let (fw, fh) = (WIDTH * filter.factor(), HEIGHT * filter.factor());
+        let n = factor / filter.factor();
+
+        let (w, h) = ((fw * n).min(self.width), (fh * n).min(self.height));
+        let (left, top) = ((self.width - w) / 2, (self.height - h) / 2);
+
+        for pixel in self.buffer.iter_mut() {
+            *pixel = 0;
+        }
+        for y in 0..h {
+            let src = &filtered[(y / n) * fw..];
+            let dst = &mut self.buffer[(top + y) * self.width + left..];
+            for x in 0..w {
+                dst[x] = src[x / n];
+            }
+        }
+
+        &self.buffer
+    }
+}
+
+/// Enlarge a frame by 2 where each pixel is turned into four by `f`,
+/// which receives the 3x3 neighbourhood `[up-left, up, up-right, left, center, right, down-left, down, down-right]`
+fn quadrants<F: Fn(&[u32; 9]) -> [u32; 4]>(src: &[u32], w: usize, h: usize, f: F, dst: &mut [u32]) {
+    let at = |x: isize, y: isize| {
+        let x = x.max(0).min(w as isize - 1) as usize;
+        let y = y.max(0).min(h as isize - 1) as usize;
+        src[x + y * w]
+    };
+
+    for y in 0..h {
+        for x in 0..w {
+            let (xi, yi) = (x as isize, y as isize);
+            let n = [
+                at(xi - 1, yi - 1), at(xi, yi - 1), at(xi + 1, yi - 1),
+                at(xi - 1, yi), at(xi, yi), at(xi + 1, yi),
+                at(xi - 1, yi + 1), at(xi, yi + 1), at(xi + 1, yi + 1),
+            ];
+            let [tl, tr, bl, br] = f(&n);
+            let p = x * 2 + y * 2 * w * 2;
+            dst[p] = tl;
+            dst[p + 1] = tr;
+            dst[p + w * 2] = bl;
+            dst[p + w * 2 + 1] = br;
+        }
+    }
+}
+
+/// The Scale2x / EPX rules
+fn scale2x(n: &[u32; 9]) -> [u32; 4] {
+    let (a, c, p, b, d) = (n[1], n[3], n[4], n[5], n[7]);
+    if c != b && a != d {
+        [
+            if c == a { a } else { p },
+            if a == b { b } else { p },
+            if d == c { c } else { p },
+            if b == d { d } else { p },
+        ]
+    } else {
+        [p; 4]
+    }
+}
+
+/// The hq2x rules, reduced to the cases that depend on the closest neighbours of each quadrant
+fn hq2x(n: &[u32; 9]) -> [u32; 4] {
+    // For each quadrant the center, the vertical and horizontal neighbour and the diagonal one
+    let quadrant = |v: u32, h: u32, d: u32| {
+        let c = n[4];
+        if !differ(v, h) && differ(c, v) {
+            if differ(c, d) {
+                blend(&[(c, 2), (v, 1), (h, 1)])
+            } else {
+                blend(&[(c, 6), (v, 1), (h, 1)])
+            }
+        } else if differ(c, d) {
+            blend(&[(c, 3), (d, 1)])
+        } else {
+            c
+        }
+    };
+
+    [
+        quadrant(n[1], n[3], n[0]),
+        quadrant(n[1], n[5], n[2]),
+        quadrant(n[7], n[3], n[6]),
+        quadrant(n[7], n[5], n[8]),
+    ]
+}
+
+/// Check if two colors differ noticeably, using the thresholds of hqx in YUV
+fn differ(a: u32, b: u32) -> bool {
+    let yuv = |c: u32| {
+        let (r, g, b) = ((c >> 16 & 0xFF) as i32, (c >> 8 & 0xFF) as i32, (c & 0xFF) as i32);
+        ((r + g + b) / 3, (r - b) / 4 + 128, (-r + 2 * g - b) / 8 + 128)
+    };
+    let ((y1, u1, v1), (y2, u2, v2)) = (yuv(a), yuv(b));
+    (y1 - y2).abs() > 48 || (u1 - u2).abs() > 7 || (v1 - v2).abs() > 6
+}
+
+/// Blend colors by their weights
+fn blend(colors: &[(u32, u32)]) -> u32 {
+    let total: u32 = colors.iter().map(|&(_, w)| w).sum();
+    let channel = |shift: u32| colors.iter().map(|&(c, w)| (c >> shift & 0xFF) * w).sum::<u32>() / total;
+    channel(16) << 16 | channel(8) << 8 | channel(0)
+}
+
+/// Enlarge a frame by 2 and darken every second line
+fn scanlines(src: &[u32], w: usize, h: usize, dst: &mut [u32]) {
+    for (y, row) in src.chunks(w).take(h).enumerate() {
+        for (x, &c) in row.iter().enumerate() {
+            let dark = (c >> 1) & 0x7F7F7F;
+            let p = x * 2 + y * 2 * w * 2;
+            dst[p] = c;
+            dst[p + 1] = c;
+            dst[p + w * 2] = dark;
+            dst[p + w * 2 + 1] = dark;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::scale::*;
+
+    fn apply(filter: Filter, src: &[u32], w: usize, h: usize) -> Vec<u32> {
+        let mut dst = Vec::new();
+        filter.apply(src, w, h, &mut dst);
+        dst
+    }
+
+    #[test]
+    fn scale2x_rounds_diagonals() {
+        // A diagonal line from the bottom left to the top right
+        let src = [0, 0, 1, 0, 1, 0, 1, 0, 0];
+        let dst = apply(Filter::Scale2x, &src, 3, 3);
+        assert_eq!(&dst[6 * 2..6 * 4], &[0, 0, 1, 1, 1, 0, 0, 1, 1, 1, 0, 0]);
+    }
+
+    #[test]
+    fn hq2x_keeps_flat_areas() {
+        let src = [0xFF0000; 9];
+        assert_eq!(apply(Filter::Hq2x, &src, 3, 3), vec![0xFF0000; 36]);
+    }
+
+    #[test]
+    fn scanlines_darken_every_second_line() {
+        let src = [0xFFFFFF, 0x804020, 0x000000, 0x0000FF];
+        assert_eq!(apply(Filter::Scanlines, &src, 2, 2), vec![
+            0xFFFFFF, 0xFFFFFF, 0x804020, 0x804020,
+            0x7F7F7F, 0x7F7F7F, 0x402010, 0x402010,
+            0x000000, 0x000000, 0x0000FF, 0x0000FF,
+            0x000000, 0x000000, 0x00007F, 0x00007F,
+        ]);
+    }
+
+    #[test]
+    fn blend_works() {
+        assert_eq!(blend(&[(0xFF0000, 1), (0x0000FF, 1)]), 0x7F007F);
+        assert_eq!(blend(&[(0x804020, 3), (0x000000, 1)]), 0x603018);
+    }
+
+    #[test]
+    fn scaler_letterboxes() {
+        let src = vec![0xFFFFFF; WIDTH * HEIGHT];
+        let mut scaler = Scaler::new(Filter::None, 700, 500);
+        assert_eq!(scaler.factor(), (2, Filter::None));
+        let dst = scaler.scale(&src).to_vec();
+        assert_eq!(dst.len(), 700 * 500);
+        // 640x480 centered with 30 and 10 pixels on each side
+        assert_eq!(dst[10 * 700 + 29], 0);
+        assert_eq!(dst[10 * 700 + 30], 0xFFFFFF);
+        assert_eq!(dst[10 * 700 + 669], 0xFFFFFF);
+        assert_eq!(dst[10 * 700 + 670], 0);
+        assert_eq!(dst[9 * 700 + 30], 0);
+    }
+
+    #[test]
+    fn scaler_reuses_its_buffers() {
+        let src = vec![0x804020; WIDTH * HEIGHT];
+        let mut scaler = Scaler::new(Filter::Scanlines, 640, 480);
+        let first = scaler.scale(&src).as_ptr();
+        let filtered = scaler.filtered.as_ptr();
+        let dst = scaler.scale(&src);
+        assert_eq!((dst[0], dst[640]), (0x804020, 0x402010));
+        assert_eq!(dst.as_ptr(), first);
+        assert_eq!(scaler.filtered.as_ptr(), filtered);
+    }
+
+    #[test]
+    fn scaler_rounds_factor_for_filters() {
+        assert_eq!(Scaler::new(Filter::Scale2x, 320, 240).factor(), (1, Filter::None));
+        assert_eq!(Scaler::new(Filter::Scale2x, 960, 720).factor(), (2, Filter::Scale2x));
+        assert_eq!(Scaler::new(Filter::Hq2x, 1280, 960).factor(), (4, Filter::Hq2x));
+    }
+}
@@ -0,0 +1,121 @@
+use std::collections::HashSet;
+use std::fs;
+
+use clap::ArgMatches;
+use serde::Serialize;
+
+use crate::cli;
+use crate::cpu::Instruction;
+use crate::rom::*;
+
+/// The result of validating a rom, as printed by the `info` subcommand
+#[derive(Serialize)]
+struct Report<'a> {
+    file: &'a str,
+    header: bool,
+    version: String,
+    size: u32,
+    file_size: u32,
+    start: u16,
+    checksum: String,
+    actual_checksum: String,
+    checks: Checks,
+    valid: bool,
+}
+
+#[derive(Serialize)]
+struct Checks {
+    size: bool,
+    start: bool,
+    version: bool,
+    checksum: bool,
+}
+
+/// Print the header fields of a rom and validate them
+pub fn info(matches: &ArgMatches) -> Result<(), String> {
+    let file = matches.value_of("ROM").unwrap();
+    let rom = Rom::load(file).map_err(|e| format!("Error loading rom file {}: {}", file, e))?;
+    let (expected, actual) = rom.checksum();
+
+    let report = Report {
+        file,
+        header: rom.has_header(),
+        version: rom.version().to_string(),
+        size: rom.size(),
+        file_size: rom.file_size(),
+        start: rom.start(),
+        checksum: format!("{:08X}", expected),
+        actual_checksum: format!("{:08X}", actual),
+        checks: Checks {
+            size: rom.size_ok(),
+            start: rom.start_ok(),
+            version: rom.version_ok(),
+            checksum: rom.checksum_ok(),
+        },
+        valid: rom.is_valid(),
+    };
+
+    if matches.is_present("json") {
+        println!("{}", serde_json::to_string_pretty(&report).map_err(|e| e.to_string())?);
+    } else {
+        let status = |ok| if ok { "OK" } else { "NOT OK" };
+        println!("  header: {}", if report.header { "CH16" } else { "none" });
+        println!(" version: {} {}", report.version, status(report.checks.version));
+        println!("    size: {} (file {}) {}", report.size, report.file_size, status(report.checks.size));
+        println!("   start: 0x{:04X} {}", report.start, status(report.checks.start));
+        println!("checksum: {} (actual {}) {}", report.checksum, report.actual_checksum, status(report.checks.checksum));
+    }
+
+    if report.valid { Ok(()) } else { Err(format!("{} is not a valid rom", file)) }
+}
+
+/// Wrap a raw binary into a rom with a `CH16` header, or repair the header of an existing rom
+pub fn pack(matches: &ArgMatches) -> Result<(), String> {
+    let (input, output) = (matches.value_of("INPUT").unwrap(), matches.value_of("OUTPUT").unwrap());
+    let rom = Rom::load(input).map_err(|e| format!("Error loading rom file {}: {}", input, e))?;
+
+    let (version, start) = if rom.has_header() { (rom.version(), rom.start()) } else { (SUPPORTED_VERSION, 0) };
+    let version = cli::value(matches, "spec").unwrap_or(version);
+    let start = matches.value_of("start").map_or(Ok(start), cli::parse_address)?;
+
+    let rom = RomBuilder::new(rom.rom().clone()).version(version).start(start).build();
+    if !rom.start_ok() {
+        return Err(format!("start address 0x{:04X} does not lie inside the rom", start));
+    }
+    rom.save(output).map_err(|e| format!("Error writing rom file {}: {}", output, e))
+}
+
+/// Strip the `CH16` header from a rom
+pub fn unpack(matches: &ArgMatches) -> Result<(), String> {
+    let (input, output) = (matches.value_of("INPUT").unwrap(), matches.value_of("OUTPUT").unwrap());
+    let rom = Rom::load(input).map_err(|e| format!("Error loading rom file {}: {}", input, e))?;
+    if !rom.has_header() {
+        return Err(format!("{} does not have a CH16 header", input));
+    }
+    fs::write(output, rom.rom()).map_err(|e| format!("Error writing file {}: {}", output, e))
+}
+
+/// Disassemble a rom, the start address and the targets of branches are marked with `>`
+/// and words that are no valid instruction are printed as data
+pub fn disasm(matches: &ArgMatches) -> Result<(), String> {
+    let file = matches.value_of("ROM").unwrap();
+    let rom = Rom::load(file).map_err(|e| format!("Error loading rom file {}: {}", file, e))?;
+    let instrs: Vec<_> = rom.rom().chunks(4).map(Instruction::decode).collect();
+
+    let mut targets: HashSet<u16> = instrs.iter().flatten()
+        .filter(|instr| instr.is_branch())
+        .filter_map(|instr| instr.hhll())
+        .collect();
+    targets.insert(rom.start());
+
+    for (i, (bytes, instr)) in rom.rom().chunks(4).zip(&instrs).enumerate() {
+        let addr = (i * 4) as u16;
+        let marker = if targets.contains(&addr) { '>' } else { ' ' };
+        let hex: Vec<_> = bytes.iter().map(|b| format!("{:02X}", b)).collect();
+        match instr {
+            Ok(instr) => println!("0x{:04X} {} {:<11}  {}", addr, marker, hex.join(" "), instr),
+            Err(_) => println!("0x{:04X} {} {:<11}  DB", addr, marker, hex.join(" ")),
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,195 @@
+use minifb::{Key, KeyRepeat, Scale, Window, WindowOptions};
+
+use crate::capture::{HEIGHT, WIDTH};
+use crate::cpu::Cpu;
+use crate::gpu::{sprite_pixels, DrawCall};
+
+/// The width of the pane that shows the memory as sprite
+const PANE: usize = 256;
+
+/// The height of the palette strip below the memory
+const SWATCH: usize = 16;
+
+const BOX_COLOR: u32 = 0x00C000;
+const OVERLAP_COLOR: u32 = 0xE00000;
+const SELECTED_COLOR: u32 = 0xFFFF00;
+
+/// A window that shows the sprites drawn in the last frame, renders memory as sprite and shows the active palette.
+///
+/// | Key                  | Description                                     |
+/// |:---------------------|:------------------------------------------------|
+/// | `Up` / `Down`        | select the previous / next sprite               |
+/// | `Enter`              | browse the memory of the selected sprite        |
+/// | `Left` / `Right`     | move the browsed address by one byte            |
+/// | `PageUp` / `PageDown`| move the browsed address by one sprite          |
+/// | `,` / `.`            | decrease / increase the browsed width           |
+/// | `-` / `=`            | decrease / increase the browsed height          |
+/// | `L`                  | list all sprites of the last frame              |
+pub struct SpriteViewer {
+    win: Window,
+    buffer: Vec<u32>,
+    selected: Option<usize>,
+    addr: u16,
+    w: u8,
+    h: u8,
+}
+
+impl SpriteViewer {
+    pub fn new() -> Result<SpriteViewer, String> {
+        let options = WindowOptions { scale: Scale::X2, ..WindowOptions::default() };
+        let win = Window::new("rchip16 - sprites", WIDTH + PANE, HEIGHT, options)
+            .map_err(|e| format!("Error creating sprite viewer: {}", e))?;
+        Ok(SpriteViewer { win, buffer: vec![0; (WIDTH + PANE) * HEIGHT], selected: None, addr: 0, w: 8, h: 16 })
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.win.is_open()
+    }
+
+    /// Handle the input of the viewer and redraw it
+    pub fn update(&mut self, cpu: &Cpu) -> Result<(), String> {
+        self.handle_input(cpu.gpu().frame_draws());
+        self.draw_frame(cpu);
+        self.draw_memory(cpu);
+        self.draw_palette(cpu);
+        self.win.update_with_buffer(&self.buffer).map_err(|e| format!("Error updating sprite viewer: {}", e))
+    }
+
+    fn handle_input(&mut self, draws: &[DrawCall]) {
+        let win = &self.win;
+        let pressed = |key| win.is_key_pressed(key, KeyRepeat::Yes);
+        let (browse_w, browse_h) = (self.w as u16, self.h as u16);
+
+        if self.selected.is_some_and(|i| i >= draws.len()) {
+            self.selected = None;
+        }
+        let selected = if pressed(Key::Down) {
+            Some(self.selected.map_or(0, |i| i + 1).min(draws.len().saturating_sub(1)))
+        } else if pressed(Key::Up) {
+            Some(self.selected.map_or(0, |i| i.saturating_sub(1)))
+        } else {
+            None
+        };
+        if let Some(i) = selected.filter(|&i| i < draws.len()) {
+            self.selected = Some(i);
+            println!("{}", format_draw(i, &draws[i]));
+        }
+
+        let mut browse = None;
+        if pressed(Key::Enter) {
+            if let Some(i) = self.selected {
+                let draw = &draws[i];
+                browse = Some((draw.addr, draw.w.max(1), draw.h.max(1)));
+            }
+        }
+        if pressed(Key::Left) { browse = Some((self.addr.wrapping_sub(1), self.w, self.h)) }
+        if pressed(Key::Right) { browse = Some((self.addr.wrapping_add(1), self.w, self.h)) }
+        if pressed(Key::PageUp) { browse = Some((self.addr.wrapping_sub(browse_w * browse_h), self.w, self.h)) }
+        if pressed(Key::PageDown) { browse = Some((self.addr.wrapping_add(browse_w * browse_h), self.w, self.h)) }
+        if pressed(Key::Comma) { browse = Some((self.addr, (self.w - 1).max(1), self.h)) }
+        if pressed(Key::Period) { browse = Some((self.addr, self.w.saturating_add(1), self.h)) }
+        if pressed(Key::Minus) { browse = Some((self.addr, self.w, (self.h - 1).max(1))) }
+        if pressed(Key::Equal) { browse = Some((self.addr, self.w, self.h.saturating_add(1))) }
+        if let Some((addr, w, h)) = browse {
+            self.addr = addr;
+            self.w = w;
+            self.h = h;
+            println!("browsing 0x{:04X} as {}x{} bytes", addr, w, h);
+        }
+
+        if win.is_key_pressed(Key::L, KeyRepeat::No) {
+            println!("{} sprites drawn in the last frame", draws.len());
+            for (i, draw) in draws.iter().enumerate() {
+                println!("{}", format_draw(i, draw));
+            }
+        }
+    }
+
+    /// Draw the last frame with the bounding boxes of all sprites
+    fn draw_frame(&mut self, cpu: &Cpu) {
+        let gpu = cpu.gpu();
+        let palette = gpu.palette();
+        for (y, row) in gpu.frame().chunks(WIDTH).enumerate() {
+            for (x, &p) in row.iter().enumerate() {
+                self.buffer[x + y * (WIDTH + PANE)] = palette[p as usize];
+            }
+        }
+
+        let (draws, selected) = (gpu.frame_draws(), self.selected);
+        for (_, draw) in draws.iter().enumerate().filter(|&(i, _)| Some(i) != selected) {
+            let color = if draw.overlap { OVERLAP_COLOR } else { BOX_COLOR };
+            self.draw_box(draw.x as i32, draw.y as i32, draw.w as i32 * 2, draw.h as i32, color);
+        }
+        if let Some(draw) = selected.and_then(|i| draws.get(i)) {
+            self.draw_box(draw.x as i32, draw.y as i32, draw.w as i32 * 2, draw.h as i32, SELECTED_COLOR);
+        }
+    }
+
+    /// Draw the outline of a rectangle, clipped to the frame
+    fn draw_box(&mut self, x: i32, y: i32, w: i32, h: i32, color: u32) {
+        if w == 0 || h == 0 {
+            return;
+        }
+        let stride = WIDTH + PANE;
+        let mut plot = |px: i32, py: i32| {
+            if px >= 0 && px < WIDTH as i32 && py >= 0 && py < HEIGHT as i32 {
+                self.buffer[px as usize + py as usize * stride] = color;
+            }
+        };
+        for px in x..x + w {
+            plot(px, y);
+            plot(px, y + h - 1);
+        }
+        for py in y..y + h {
+            plot(x, py);
+            plot(x + w - 1, py);
+        }
+    }
+
+    /// Draw the browsed memory as sprite, enlarged to fit the pane
+    fn draw_memory(&mut self, cpu: &Cpu) {
+        let stride = WIDTH + PANE;
+        let memory = cpu.memory();
+        let len = self.w as usize * self.h as usize;
+        let data: Vec<u8> = (0..len).map(|i| memory[(self.addr as usize + i) & 0xFFFF]).collect();
+        let pixels = sprite_pixels(&data, self.w, self.h);
+        let palette = cpu.gpu().palette();
+
+        let (w, h) = (self.w as usize * 2, self.h as usize);
+        let zoom = (PANE / w).min((HEIGHT - SWATCH) / h).max(1);
+
+        for y in 0..HEIGHT - SWATCH {
+            for x in 0..PANE {
+                let (sx, sy) = (x / zoom, y / zoom);
+                self.buffer[WIDTH + x + y * stride] = if sx < w && sy < h {
+                    match pixels[sx + sy * w] {
+                        // Show transparent pixels as checkerboard
+                        0 => if (x / 4 + y / 4) % 2 == 0 { 0x404040 } else { 0x202020 },
+                        p => palette[p as usize],
+                    }
+                } else {
+                    0x101010
+                };
+            }
+        }
+    }
+
+    /// Draw the active palette as strip of 16 swatches below the memory
+    fn draw_palette(&mut self, cpu: &Cpu) {
+        let stride = WIDTH + PANE;
+        let palette = cpu.gpu().palette();
+        for y in HEIGHT - SWATCH..HEIGHT {
+            for x in 0..PANE {
+                self.buffer[WIDTH + x + y * stride] = palette[x * 16 / PANE];
+            }
+        }
+    }
+}
+
+/// Format a draw call for printing
+pub fn format_draw(index: usize, draw: &DrawCall) -> String {
+    format!("#{:<3} addr 0x{:04X}  pos {:>4},{:>4}  size {:>3}x{:<3}  flip {}{}  {}",
+            index, draw.addr, draw.x, draw.y, draw.w, draw.h,
+            if draw.hflip { "H" } else { "-" }, if draw.vflip { "V" } else { "-" },
+            if draw.overlap { "overlap" } else { "" })
+}
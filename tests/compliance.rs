@@ -0,0 +1,284 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::fmt::Write;
+use std::fs;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+
+use hound::{SampleFormat, WavReader, WavSpec, WavWriter};
+use serde::Deserialize;
+
+use rchip16::apu::Apu;
+use rchip16::cpu::Cpu;
+use rchip16::golden;
+use rchip16::gpu::Gpu;
+use rchip16::rom::Rom;
+
+/// The number of instructions per frame at 1 MHz
+const FRAME_INSTRUCTIONS: usize = 1_000_000 / 60;
+
+/// The largest difference of a 16 bit audio sample that is accepted
+const AUDIO_TOLERANCE: i32 = 2;
+
+/// The list of test roms in `tests/roms/manifest.toml`
+#[derive(Deserialize)]
+struct Manifest {
+    rom: Vec<Entry>,
+}
+
+#[derive(Deserialize)]
+struct Entry {
+    file: String,
+    /// The area of the specification the rom tests (e.g. `flags`)
+    area: String,
+    frames: u64,
+    /// The expected frame after running the rom
+    image: Option<String>,
+    /// The expected audio of all frames as WAV
+    audio: Option<String>,
+}
+
+enum Outcome {
+    Passed,
+    Failed(String),
+    Skipped,
+}
+
+fn roms_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("roms")
+}
+
+/// Run a rom headless and compare the result, catching panics of the core
+fn run(dir: &Path, entry: &Entry) -> Outcome {
+    let path = dir.join(&entry.file);
+    if !path.exists() {
+        return Outcome::Skipped;
+    }
+    match panic::catch_unwind(AssertUnwindSafe(|| check(dir, &path, entry))) {
+        Ok(Ok(())) => Outcome::Passed,
+        Ok(Err(e)) => Outcome::Failed(e),
+        Err(e) => Outcome::Failed(format!("panicked: {}", e.downcast_ref::<String>().map_or("", |s| s.as_str()))),
+    }
+}
+
+fn check(dir: &Path, path: &Path, entry: &Entry) -> Result<(), String> {
+    let rom = Rom::load(path).map_err(|e| format!("Error loading rom: {}", e))?;
+    let apu = Apu::silent(1.0);
+    let rate = apu.sample_rate() as u64;
+    let mut cpu = Cpu::new(Gpu::new(), apu, &rom);
+    cpu.set_seed(0);
+
+    let mut buffer = vec![0; 320 * 240];
+    let mut samples = Vec::new();
+    for frame in 1..=entry.frames {
+        for _ in 0..FRAME_INSTRUCTIONS {
+            cpu.step();
+            if cpu.wait_vblank() {
+                break;
+            }
+        }
+        cpu.render(&mut buffer);
+        let count = frame * rate / 60 - samples.len() as u64;
+        samples.extend(cpu.apu_mut().samples(count as usize).iter().map(|&s| (s * i16::MAX as f32) as i16));
+    }
+
+    if let Some(ref image) = entry.image {
+        golden::compare(cpu.gpu(), dir.join(image))?;
+    }
+    if let Some(ref audio) = entry.audio {
+        compare_audio(&samples, rate as u32, &dir.join(audio))?;
+    }
+    Ok(())
+}
+
+/// Compare samples against a mono 16 bit WAV file, or write it when blessing
+fn compare_audio(samples: &[i16], sample_rate: u32, path: &Path) -> Result<(), String> {
+    if env::var_os("RCHIP16_BLESS").is_some() {
+        let spec = WavSpec { channels: 1, sample_rate, bits_per_sample: 16, sample_format: SampleFormat::Int };
+        let mut writer = WavWriter::create(path, spec).map_err(|e| e.to_string())?;
+        for &sample in samples {
+            writer.write_sample(sample).map_err(|e| e.to_string())?;
+        }
+        return writer.finalize().map_err(|e| e.to_string());
+    }
+
+    let mut reader = WavReader::open(path).map_err(|e| format!("Error opening {}: {}", path.display(), e))?;
+    let expected: Vec<i16> = reader.samples().collect::<Result<_, _>>().map_err(|e| e.to_string())?;
+    if expected.len() != samples.len() {
+        return Err(format!("expected {} samples, got {}", expected.len(), samples.len()));
+    }
+    match expected.iter().zip(samples).position(|(&a, &b)| (a as i32 - b as i32).abs() > AUDIO_TOLERANCE) {
+        Some(i) => Err(format!("sample {} differs from {}: expected {}, got {}", i, path.display(), expected[i], samples[i])),
+        None => Ok(()),
+    }
+}
+
+/// Run all roms of the manifest and return the report per area and whether all passed
+fn report(dir: &Path) -> (String, bool) {
+    let manifest = fs::read_to_string(dir.join("manifest.toml")).unwrap();
+    let manifest: Manifest = toml::from_str(&manifest).unwrap();
+
+    let mut areas: BTreeMap<&str, (usize, usize, usize)> = BTreeMap::new();
+    let mut failures = String::new();
+    for entry in &manifest.rom {
+        let counts = areas.entry(&entry.area).or_default();
+        match run(dir, entry) {
+            Outcome::Passed => counts.0 += 1,
+            Outcome::Failed(e) => {
+                counts.1 += 1;
+                writeln!(failures, "{} ({}): {}", entry.file, entry.area, e).unwrap();
+            },
+            Outcome::Skipped => counts.2 += 1,
+        }
+    }
+
+    let mut report = format!("{:<10} {:>6} {:>6} {:>7}\n", "area", "passed", "failed", "skipped");
+    for (area, (passed, failed, skipped)) in &areas {
+        writeln!(report, "{:<10} {:>6} {:>6} {:>7}", area, passed, failed, skipped).unwrap();
+    }
+    report += &failures;
+    (report, failures.is_empty())
+}
+
+#[test]
+fn compliance_roms_pass() {
+    let (report, passed) = report(&roms_dir());
+    println!("{}", report);
+    assert!(passed, "\n{}", report);
+}
+
+// The expectations in `tests/roms` were recorded with `RCHIP16_BLESS`, so the tests below check them
+// against frames and audio computed by hand from the assembly of the roms and the specification
+
+/// The default palette of the specification
+const PALETTE: [u32; 16] = [0x000000, 0x000000, 0x888888, 0xBF3932, 0xDE7AAE, 0x4C3D21, 0x905F25, 0xE49452,
+                            0xEAD979, 0x537A3B, 0xABD54A, 0x252E38, 0x00467F, 0x68ABCC, 0xBCDEE4, 0xFFFFFF];
+
+const C: u8 = 1 << 1;
+const Z: u8 = 1 << 2;
+const O: u8 = 1 << 6;
+const N: u8 = 1 << 7;
+
+/// Draw a sprite of `width` bytes like `DRW`: color 0 is transparent and pixels off screen are clipped
+fn plot(frame: &mut [u8], x: i32, y: i32, width: usize, data: &[u8], (hflip, vflip): (bool, bool)) {
+    let height = data.len() / width;
+    for row in 0..height {
+        for col in 0..width * 2 {
+            let src_row = if vflip { height - 1 - row } else { row };
+            let src_col = if hflip { width * 2 - 1 - col } else { col };
+            let byte = data[src_row * width + src_col / 2];
+            let pixel = if src_col % 2 == 0 { byte >> 4 } else { byte & 0xF };
+            let (px, py) = (x + col as i32, y + row as i32);
+            if pixel != 0 && (0..320).contains(&px) && (0..240).contains(&py) {
+                frame[(px + py * 320) as usize] = pixel;
+            }
+        }
+    }
+}
+
+fn expected(name: &str) -> Vec<u32> {
+    golden::load_png(roms_dir().join(name)).unwrap()
+}
+
+#[test]
+fn flags_image_matches_hand_computed_results() {
+    // The result and flags of every case in `flags.asm`, with the flags cleared before each operation
+    let cases: [(i16, u8); 25] = [
+        (3, 0),                     // add 1, 2
+        (-0x8000, O | N),           // add 32767, 1
+        (0, C | Z),                 // add -1, 1
+        (0, C | Z | O),             // add -32768, -32768
+        (2, 0),                     // sub 5, 3
+        (-2, C | N),                // sub 3, 5
+        (0x7FFF, O),                // sub -32768, 1
+        (0, Z),                     // sub 7, 7
+        (0, C | Z | O),             // mul 256, 256
+        (-6, C | N),                // mul -2, 3
+        (-25536, O | N),            // mul 200, 200
+        (3, C),                     // div 7, 2
+        (-3, C | N),                // div -7, 2
+        (-0x8000, O | N),           // div -32768, -1
+        (2, 0),                     // mod -7, 3
+        (-2, N),                    // mod 7, -3
+        (-1, N),                    // rem -7, 3
+        (1, 0),                     // rem 7, -3
+        (0x000F, 0),                // and 3855, 255
+        (-0x7100, N),               // or 3840, -32768
+        (0, Z),                     // xor -1, -1
+        (-0x8000, N),               // shl 1, 15
+        (1, 0),                     // shr -32768, 15
+        (-1, N),                    // sar -32768, 15
+        (-0x8000, N),               // neg -32768
+    ];
+    let data: Vec<u8> = cases.iter().flat_map(|&(res, flags)| {
+        let [lo, hi] = res.to_le_bytes();
+        [lo, hi, flags, 0]
+    }).collect();
+
+    let mut frame = vec![0; 320 * 240];
+    plot(&mut frame, 8, 8, 4, &data, (false, false));
+    let frame: Vec<u32> = frame.iter().map(|&p| PALETTE[p as usize]).collect();
+    assert!(frame == expected("flags.png"));
+}
+
+#[test]
+fn drw_clip_image_matches_hand_computed_frame() {
+    let sprite = [0x12, 0x34, 0x56, 0x78, 0x9A, 0xBC, 0xDE, 0xF1, 0x23, 0x45, 0x67, 0x89, 0xAB, 0xCD, 0xEF, 0x12];
+    let draws = [
+        (-4, -2), (316, -2), (-4, 238), (316, 238), (-7, 100), (319, 100), (100, -3), (100, 239),
+        (-8, 50), (320, 50), (50, -4), (50, 240),
+    ];
+
+    let mut frame = vec![0; 320 * 240];
+    for &(x, y) in &draws {
+        plot(&mut frame, x, y, 4, &sprite, (false, false));
+    }
+    plot(&mut frame, 40, 40, 4, &sprite, (true, false));
+    plot(&mut frame, 60, 40, 4, &sprite, (false, true));
+    plot(&mut frame, 80, 40, 4, &sprite, (true, true));
+    plot(&mut frame, 150, 120, 4, &sprite, (false, false));
+    plot(&mut frame, 152, 121, 4, &sprite, (false, false));
+    // Only the second of the overlapping draws sets the carry
+    plot(&mut frame, 200, 200, 4, &[0, 0, C, 0], (false, false));
+    let frame: Vec<u32> = frame.iter().map(|&p| PALETTE[p as usize]).collect();
+    assert!(frame == expected("drw_clip.png"));
+}
+
+#[test]
+fn palette_image_matches_hand_computed_frame() {
+    let palette = [0x000000, 0x101010, 0xFF0000, 0x00FF00, 0x0000FF, 0x203040, 0xFFFF00, 0x00FFFF,
+                   0xFF00FF, 0x808080, 0xC0C0C0, 0x400000, 0x004000, 0x000040, 0x7F7F00, 0xFFFFFF];
+    // Color 0 of the strip is transparent and shows the background color 5
+    let frame: Vec<u32> = (0..320 * 240).map(|i| match i % 320 {
+        x @ 1..=15 => palette[x],
+        _ => palette[5],
+    }).collect();
+    assert!(frame == expected("palette.png"));
+}
+
+#[test]
+fn sound_audio_matches_hand_computed_timing() {
+    let mut reader = WavReader::open(roms_dir().join("sound.wav")).unwrap();
+    let rate = reader.spec().sample_rate as usize;
+    let samples: Vec<i16> = reader.samples().collect::<Result<_, _>>().unwrap();
+    let ms = |ms: usize| ms * rate / 1000;
+    // The frequency of the samples in `range` from the number of sign changes
+    let frequency = |range: std::ops::Range<usize>| {
+        let s = &samples[range.clone()];
+        let changes = s.windows(2).filter(|w| (w[0] < 0) != (w[1] < 0)).count();
+        changes as f64 / 2.0 * rate as f64 / range.len() as f64
+    };
+
+    // 50 frames, SND1 plays 500 Hz for 100 ms
+    assert_eq!(samples.len(), rate * 50 / 60);
+    assert!((frequency(0..ms(100)) - 500.0).abs() < 10.0);
+    // Silence until the 10 frames of waiting are over
+    assert!(samples[ms(101)..ms(160)].iter().all(|&s| s == 0));
+    // Three waves of 440 Hz of 150 ms each, started every 10 frames
+    for wave in 0..3 {
+        let start = ms(167) + wave * rate / 6;
+        assert!((frequency(start + ms(10)..start + ms(140)) - 440.0).abs() < 10.0);
+    }
+    // SND0 stops the sound after the last wave
+    assert!(samples[ms(700)..].iter().all(|&s| s == 0));
+}
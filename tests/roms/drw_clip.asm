@@ -0,0 +1,80 @@
+; DRW clipping and flipping: draws a 8x4 pixel sprite partially and fully off screen at every edge,
+; flipped in all directions and twice overlapping, storing the flags after each overlapping draw
+
+    spr 0x0404
+    ldi r1, -4
+    ldi r2, -2
+    drw r1, r2, sprite
+    ldi r1, 316
+    ldi r2, -2
+    drw r1, r2, sprite
+    ldi r1, -4
+    ldi r2, 238
+    drw r1, r2, sprite
+    ldi r1, 316
+    ldi r2, 238
+    drw r1, r2, sprite
+    ldi r1, -7
+    ldi r2, 100
+    drw r1, r2, sprite
+    ldi r1, 319
+    ldi r2, 100
+    drw r1, r2, sprite
+    ldi r1, 100
+    ldi r2, -3
+    drw r1, r2, sprite
+    ldi r1, 100
+    ldi r2, 239
+    drw r1, r2, sprite
+    ldi r1, -8
+    ldi r2, 50
+    drw r1, r2, sprite
+    ldi r1, 320
+    ldi r2, 50
+    drw r1, r2, sprite
+    ldi r1, 50
+    ldi r2, -4
+    drw r1, r2, sprite
+    ldi r1, 50
+    ldi r2, 240
+    drw r1, r2, sprite
+    flip 1, 0
+    ldi r1, 40
+    ldi r2, 40
+    drw r1, r2, sprite
+    flip 0, 1
+    ldi r1, 60
+    ldi r2, 40
+    drw r1, r2, sprite
+    flip 1, 1
+    ldi r1, 80
+    ldi r2, 40
+    drw r1, r2, sprite
+    flip 0, 0
+    ; overlapping draws set the carry
+    ldi r1, 150
+    ldi r2, 120
+    drw r1, r2, sprite
+    pushf
+    pop r5
+    stm r5, flags
+    ldi r1, 152
+    ldi r2, 121
+    drw r1, r2, sprite
+    pushf
+    pop r5
+    stm r5, flags+2
+    spr 0x0104
+    ldi r1, 200
+    ldi r2, 200
+    drw r1, r2, flags
+halt:
+    vblnk
+    jmp halt
+sprite:
+    db 0x12, 0x34, 0x56, 0x78
+    db 0x9A, 0xBC, 0xDE, 0xF1
+    db 0x23, 0x45, 0x67, 0x89
+    db 0xAB, 0xCD, 0xEF, 0x12
+flags:
+    db 0x00, 0x00, 0x00, 0x00
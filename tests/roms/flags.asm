@@ -0,0 +1,211 @@
+; Flags: runs arithmetic corner cases and stores the result and the flags of each,
+; then draws them as a sprite of 4 bytes per case (result low, result high, flags, 0) at (8, 8)
+
+    ; add 1, 2
+    ldi r1, 1
+    ldi r2, 2
+    add r1, r2
+    pushf
+    pop r5
+    stm r1, results+0
+    stm r5, results+2
+    ; add 32767, 1
+    ldi r1, 32767
+    ldi r2, 1
+    add r1, r2
+    pushf
+    pop r5
+    stm r1, results+4
+    stm r5, results+6
+    ; add -1, 1
+    ldi r1, -1
+    ldi r2, 1
+    add r1, r2
+    pushf
+    pop r5
+    stm r1, results+8
+    stm r5, results+10
+    ; add -32768, -32768
+    ldi r1, -32768
+    ldi r2, -32768
+    add r1, r2
+    pushf
+    pop r5
+    stm r1, results+12
+    stm r5, results+14
+    ; sub 5, 3
+    ldi r1, 5
+    ldi r2, 3
+    sub r1, r2
+    pushf
+    pop r5
+    stm r1, results+16
+    stm r5, results+18
+    ; sub 3, 5
+    ldi r1, 3
+    ldi r2, 5
+    sub r1, r2
+    pushf
+    pop r5
+    stm r1, results+20
+    stm r5, results+22
+    ; sub -32768, 1
+    ldi r1, -32768
+    ldi r2, 1
+    sub r1, r2
+    pushf
+    pop r5
+    stm r1, results+24
+    stm r5, results+26
+    ; sub 7, 7
+    ldi r1, 7
+    ldi r2, 7
+    sub r1, r2
+    pushf
+    pop r5
+    stm r1, results+28
+    stm r5, results+30
+    ; mul 256, 256
+    ldi r1, 256
+    ldi r2, 256
+    mul r1, r2
+    pushf
+    pop r5
+    stm r1, results+32
+    stm r5, results+34
+    ; mul -2, 3
+    ldi r1, -2
+    ldi r2, 3
+    mul r1, r2
+    pushf
+    pop r5
+    stm r1, results+36
+    stm r5, results+38
+    ; mul 200, 200
+    ldi r1, 200
+    ldi r2, 200
+    mul r1, r2
+    pushf
+    pop r5
+    stm r1, results+40
+    stm r5, results+42
+    ; div 7, 2
+    ldi r1, 7
+    ldi r2, 2
+    div r1, r2
+    pushf
+    pop r5
+    stm r1, results+44
+    stm r5, results+46
+    ; div -7, 2
+    ldi r1, -7
+    ldi r2, 2
+    div r1, r2
+    pushf
+    pop r5
+    stm r1, results+48
+    stm r5, results+50
+    ; div -32768, -1
+    ldi r1, -32768
+    ldi r2, -1
+    div r1, r2
+    pushf
+    pop r5
+    stm r1, results+52
+    stm r5, results+54
+    ; mod -7, 3
+    ldi r1, -7
+    ldi r2, 3
+    mod r1, r2
+    pushf
+    pop r5
+    stm r1, results+56
+    stm r5, results+58
+    ; mod 7, -3
+    ldi r1, 7
+    ldi r2, -3
+    mod r1, r2
+    pushf
+    pop r5
+    stm r1, results+60
+    stm r5, results+62
+    ; rem -7, 3
+    ldi r1, -7
+    ldi r2, 3
+    rem r1, r2
+    pushf
+    pop r5
+    stm r1, results+64
+    stm r5, results+66
+    ; rem 7, -3
+    ldi r1, 7
+    ldi r2, -3
+    rem r1, r2
+    pushf
+    pop r5
+    stm r1, results+68
+    stm r5, results+70
+    ; and 3855, 255
+    ldi r1, 3855
+    ldi r2, 255
+    and r1, r2
+    pushf
+    pop r5
+    stm r1, results+72
+    stm r5, results+74
+    ; or 3840, -32768
+    ldi r1, 3840
+    ldi r2, -32768
+    or r1, r2
+    pushf
+    pop r5
+    stm r1, results+76
+    stm r5, results+78
+    ; xor -1, -1
+    ldi r1, -1
+    ldi r2, -1
+    xor r1, r2
+    pushf
+    pop r5
+    stm r1, results+80
+    stm r5, results+82
+    ; shl 1, 15
+    ldi r1, 1
+    ldi r2, 15
+    shl r1, r2
+    pushf
+    pop r5
+    stm r1, results+84
+    stm r5, results+86
+    ; shr -32768, 15
+    ldi r1, -32768
+    ldi r2, 15
+    shr r1, r2
+    pushf
+    pop r5
+    stm r1, results+88
+    stm r5, results+90
+    ; sar -32768, 15
+    ldi r1, -32768
+    ldi r2, 15
+    sar r1, r2
+    pushf
+    pop r5
+    stm r1, results+92
+    stm r5, results+94
+    ; neg -32768
+    ldi r1, -32768
+    neg r1
+    pushf
+    pop r5
+    stm r1, results+96
+    stm r5, results+98
+    spr 0x1904
+    ldi r8, 8
+    ldi r9, 8
+    drw r8, r9, results
+halt:
+    vblnk
+    jmp halt
+results:
+    db 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00
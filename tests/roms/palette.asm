@@ -0,0 +1,35 @@
+; Palette: draws a strip of all 16 colors on every line, then loads a custom palette with PAL
+; and sets the background to color 5
+
+    spr 0x0108
+    ldi r1, 0
+    ldi r2, 0
+line:
+    drw r1, r2, strip
+    addi r2, 1
+    cmpi r2, 240
+    jnz line
+    pal colors
+    bgc 5
+halt:
+    vblnk
+    jmp halt
+strip:
+    db 0x01, 0x23, 0x45, 0x67, 0x89, 0xAB, 0xCD, 0xEF
+colors:
+    db 0x00, 0x00, 0x00
+    db 0x10, 0x10, 0x10
+    db 0xFF, 0x00, 0x00
+    db 0x00, 0xFF, 0x00
+    db 0x00, 0x00, 0xFF
+    db 0x20, 0x30, 0x40
+    db 0xFF, 0xFF, 0x00
+    db 0x00, 0xFF, 0xFF
+    db 0xFF, 0x00, 0xFF
+    db 0x80, 0x80, 0x80
+    db 0xC0, 0xC0, 0xC0
+    db 0x40, 0x00, 0x00
+    db 0x00, 0x40, 0x00
+    db 0x00, 0x00, 0x40
+    db 0x7F, 0x7F, 0x00
+    db 0xFF, 0xFF, 0xFF
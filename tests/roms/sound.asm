@@ -0,0 +1,37 @@
+; Sound: plays SND1 for 100 ms, waits 10 frames, then plays 440 Hz with SNG envelopes
+; for the triangle, sawtooth and pulse waves and stops with SND0
+
+    snd1 100
+    ldi r3, 10
+wait1:
+    vblnk
+    subi r3, 1
+    jnz wait1
+    ldi r1, hz
+    sng 0x12, 0xC0A3
+    snp r1, 150
+    ldi r3, 10
+wait_wave0:
+    vblnk
+    subi r3, 1
+    jnz wait_wave0
+    sng 0x12, 0xC1A3
+    snp r1, 150
+    ldi r3, 10
+wait_wave1:
+    vblnk
+    subi r3, 1
+    jnz wait_wave1
+    sng 0x12, 0xC2A3
+    snp r1, 150
+    ldi r3, 10
+wait_wave2:
+    vblnk
+    subi r3, 1
+    jnz wait_wave2
+    snd0
+halt:
+    vblnk
+    jmp halt
+hz:
+    dw 0x01B8